@@ -0,0 +1,42 @@
+//! Capture build info printed by `shadow-tls --version`.
+
+use std::process::Command;
+
+fn output(cmd: &str, args: &[&str]) -> String {
+    Command::new(cmd)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let mut features = vec!["metrics"];
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") {
+        features.push("splice");
+    }
+
+    println!(
+        "cargo:rustc-env=SHADOW_TLS_COMMIT={}",
+        output("git", &["rev-parse", "--short", "HEAD"])
+    );
+    println!(
+        "cargo:rustc-env=SHADOW_TLS_BUILD_TIME={}",
+        output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"])
+    );
+    println!(
+        "cargo:rustc-env=SHADOW_TLS_RUSTC={}",
+        output(&rustc, &["--version"])
+    );
+    println!(
+        "cargo:rustc-env=SHADOW_TLS_FEATURES={}",
+        features.join(", ")
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
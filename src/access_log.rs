@@ -0,0 +1,170 @@
+//! Access log of finished connections written by --access-log.
+//!
+//! One line per connection closed after it was accepted, in the common log format with
+//! extra fields appended, so tools reading the common log format get the first seven:
+//!
+//! ```text
+//! 203.0.113.7 - - [15/Nov/2022:08:30:00 +0000] "CONNECT www.example.com TLS" 200 5678 1234 1520 ok
+//! ```
+//!
+//! - client ip, `-` for unix socket peers
+//! - `-` and `-` for the unused identity and user fields
+//! - UTC time the connection was closed
+//! - `"CONNECT <sni> TLS"`, sni is `-` if unknown and escaped like `\x20` outside
+//!   printable ascii
+//! - status: 200 if relayed, 400 for a failed tls handshake, 403 for a bad password,
+//!   408 for a timeout, 502 for a failed upstream connect, 500 otherwise
+//! - bytes sent to the client
+//! - bytes received from the client
+//! - duration in milliseconds
+//! - close reason: `ok`, or the error category like `bad_password` or `relay`
+
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::RelayError,
+    listener::PeerAddr,
+    logfile::{unix_secs, utc_fields},
+    metrics::Relayed,
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+    // Write failures are warned once instead of for every connection.
+    failed: AtomicBool,
+}
+
+impl AccessLog {
+    /// Append to the file at path, or write to stdout if path is `-`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = match path.to_str() {
+            Some("-") => Box::new(io::stdout()),
+            _ => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        Ok(Self {
+            out: Mutex::new(out),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Log the connection from peer closed with result after duration.
+    pub fn log(
+        &self,
+        peer: &PeerAddr,
+        sni: Option<&str>,
+        duration: Duration,
+        result: &Result<Relayed, RelayError>,
+    ) {
+        let line = format_line(unix_secs(SystemTime::now()), peer, sni, duration, result);
+        // Written at once so lines from worker threads never interleave.
+        let res = self.out.lock().unwrap().write_all(line.as_bytes());
+        if let Err(e) = res {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                tracing::warn!("Write access log failed: {e}");
+            }
+        }
+    }
+}
+
+fn format_line(
+    secs: u64,
+    peer: &PeerAddr,
+    sni: Option<&str>,
+    duration: Duration,
+    result: &Result<Relayed, RelayError>,
+) -> String {
+    let mut line = match peer {
+        PeerAddr::Tcp(addr) => addr.ip().to_string(),
+        PeerAddr::Unix => "-".to_string(),
+    };
+    let [year, month, day, hour, minute, second] = utc_fields(secs);
+    let _ = write!(
+        line,
+        " - - [{day:02}/{}/{year:04}:{hour:02}:{minute:02}:{second:02} +0000] \"CONNECT ",
+        MONTHS[month as usize - 1]
+    );
+    match sni {
+        Some(sni) if !sni.is_empty() => {
+            for b in sni.bytes() {
+                if b.is_ascii_graphic() && !matches!(b, b'"' | b'\\') {
+                    line.push(b as char);
+                } else {
+                    let _ = write!(line, "\\x{b:02x}");
+                }
+            }
+        }
+        _ => line.push('-'),
+    }
+    let (status, reason) = match result {
+        Ok(_) => (200, "ok"),
+        Err(e) => {
+            let status = match e {
+                RelayError::Relay(..) => 200,
+                RelayError::TlsHandshake(_) => 400,
+                RelayError::BadPassword => 403,
+                RelayError::Timeout => 408,
+                RelayError::UpstreamConnect(_) => 502,
+                RelayError::Other(_) => 500,
+            };
+            (status, e.category())
+        }
+    };
+    let (up, down) = match result {
+        Ok(relayed) | Err(RelayError::Relay(relayed, _)) => (relayed.inbound, relayed.outbound),
+        Err(_) => (0, 0),
+    };
+    let _ = writeln!(
+        line,
+        " TLS\" {status} {down} {up} {} {reason}",
+        duration.as_millis()
+    );
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let peer = PeerAddr::Tcp("203.0.113.7:40000".parse().unwrap());
+        let relayed = Relayed {
+            inbound: 1234,
+            outbound: 5678,
+            duration: Duration::from_millis(1520),
+        };
+        let secs = 1668501000;
+        assert_eq!(
+            format_line(secs, &peer, Some("www.example.com"), relayed.duration, &Ok(relayed)),
+            "203.0.113.7 - - [15/Nov/2022:08:30:00 +0000] \"CONNECT www.example.com TLS\" 200 5678 1234 1520 ok\n"
+        );
+        assert_eq!(
+            format_line(
+                secs,
+                &PeerAddr::Unix,
+                Some("a \"b"),
+                Duration::from_millis(3),
+                &Err(RelayError::BadPassword)
+            ),
+            "- - - [15/Nov/2022:08:30:00 +0000] \"CONNECT a\\x20\\x22b TLS\" 403 0 0 3 bad_password\n"
+        );
+        assert!(
+            format_line(secs, &peer, None, Duration::ZERO, &Err(RelayError::Timeout))
+                .contains("\"CONNECT - TLS\" 408 0 0 0 timeout")
+        );
+    }
+}
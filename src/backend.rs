@@ -0,0 +1,84 @@
+//! Data server selection by the routing tag in the first frame.
+//!
+//! A tagged first frame starts with bytes 8..16 of the hmac instead of the first 8, so
+//! the server tells it apart from the first frame of an untagged client. The tag follows
+//! as one length byte and the tag itself, then the data.
+
+use std::{fmt::Display, str::FromStr};
+
+/// Backend maps a routing tag to a data server, written as `tag=address`.
+#[derive(Debug, Clone)]
+pub struct Backend {
+    tag: String,
+    address: String,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tag, address) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expect tag=address, got {s}"))?;
+        if tag.is_empty() || tag.len() > u8::MAX as usize || address.is_empty() {
+            anyhow::bail!("expect tag=address with a tag of 1 to 255 bytes, got {s}");
+        }
+        crate::util::validate_address("data server", address)?;
+        Ok(Self {
+            tag: tag.to_string(),
+            address: address.to_string(),
+        })
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.tag, self.address)
+    }
+}
+
+/// Address of the backend with tag.
+pub fn route<'a>(backends: &'a [Backend], tag: &str) -> Option<&'a str> {
+    backends
+        .iter()
+        .find(|b| b.tag == tag)
+        .map(|b| b.address.as_str())
+}
+
+/// Append the tag to buf.
+pub fn encode_tag(tag: &str, buf: &mut Vec<u8>) {
+    buf.push(tag.len() as u8);
+    buf.extend_from_slice(tag.as_bytes());
+}
+
+/// Decode a tag from the front of buf, returns it with the bytes consumed.
+pub fn decode_tag(buf: &[u8]) -> anyhow::Result<(&str, usize)> {
+    let len = *buf.first().ok_or_else(|| anyhow::anyhow!("missing tag"))? as usize;
+    let tag = buf
+        .get(1..1 + len)
+        .ok_or_else(|| anyhow::anyhow!("truncated tag"))?;
+    let tag = std::str::from_utf8(tag).map_err(|_| anyhow::anyhow!("tag is not utf8"))?;
+    Ok((tag, 1 + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag() {
+        let backends: Vec<Backend> = ["web=127.0.0.1:8080", "dns=127.0.0.1:5353"]
+            .iter()
+            .map(|b| b.parse().unwrap())
+            .collect();
+        let mut buf = Vec::new();
+        encode_tag("dns", &mut buf);
+        buf.extend_from_slice(b"data");
+        let (tag, len) = decode_tag(&buf).unwrap();
+        assert_eq!(&buf[len..], b"data");
+        assert_eq!(route(&backends, tag), Some("127.0.0.1:5353"));
+        assert_eq!(route(&backends, "ssh"), None);
+        assert!(decode_tag(&buf[..3]).is_err());
+        assert!("=127.0.0.1:8080".parse::<Backend>().is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Bench subcommand: relay through an in-process server and client over loopback, print
+//! throughput and handshakes per second and exit.
+
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use monoio::{
+    buf::IoBuf,
+    io::{AsyncReadRent, AsyncReadRentExt, AsyncWriteRentExt},
+    net::{TcpListener, TcpStream},
+};
+use shadow_tls::{
+    client::{HandshakeOpts, Target, TlsVersion},
+    listener::PeerAddr,
+    metrics::Metrics,
+    server::DataServer,
+    upstream::{Health, LbPolicy, Upstreams},
+    util::{mod_tcp_conn, Direction},
+    verify::CertChangeAction,
+    Opts, ShadowTlsClient, ShadowTlsServer,
+};
+
+/// Self-signed certificate of localhost for the handshake server, the client skips the
+/// verification.
+const CERT: &[u8] = include_bytes!("bench/cert.der");
+const KEY: &[u8] = include_bytes!("bench/key.der");
+
+const SERVER_NAME: &str = "localhost";
+const PASSWORD: &str = "bench";
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BenchArgs {
+    #[clap(
+        long = "duration",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds to measure each of throughput and handshakes"
+    )]
+    duration: u64,
+    #[clap(
+        long = "connections",
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Concurrent connections"
+    )]
+    connections: u32,
+    #[clap(
+        long = "chunk-size",
+        default_value_t = 16384,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Bytes written and echoed back at a time when measuring throughput"
+    )]
+    chunk_size: u32,
+}
+
+/// Run the bench, returns whether it could be set up.
+pub fn run(args: BenchArgs, opts: Opts) -> bool {
+    let runtime_opts = opts.clone();
+    match crate::block_on(&runtime_opts, bench(args, opts)).expect("unable to build monoio runtime")
+    {
+        Ok(()) => true,
+        Err(e) => {
+            println!("bench failed: {e:#}");
+            false
+        }
+    }
+}
+
+async fn bench(args: BenchArgs, opts: Opts) -> anyhow::Result<()> {
+    let tls_addr = spawn_handshake_server()?;
+    let echo_addr = spawn_echo_server()?;
+    let metrics = Arc::new(Metrics::default());
+
+    let server = ShadowTlsServer::new(
+        tls_addr.to_string(),
+        DataServer::Fixed {
+            address: echo_addr.to_string(),
+            proxy_protocol: false,
+            backends: Vec::new(),
+        },
+        vec![PASSWORD.to_string()],
+        Vec::new(),
+        opts.clone(),
+        metrics.clone(),
+        None,
+    );
+    let server = Rc::new(server);
+    let server_listener = TcpListener::bind("127.0.0.1:0")?;
+    let server_addr = server_listener.local_addr()?;
+    let server_opts = opts.clone();
+    spawn_accept(server_listener, move |mut stream, addr| {
+        mod_tcp_conn(&mut stream, &server_opts, Direction::Inbound);
+        let server = server.clone();
+        async move {
+            let _ = server.relay(stream, addr).await;
+        }
+    });
+
+    let addresses = vec![server_addr.to_string()];
+    let health = Arc::new(Health::new(addresses.clone()));
+    let client = ShadowTlsClient::new(
+        HandshakeOpts {
+            server_names: vec![SERVER_NAME.to_string()],
+            alpn: Vec::new(),
+            pins: Vec::new(),
+            insecure: true,
+            verify_name: None,
+            min_version: TlsVersion::V1_2,
+            max_version: TlsVersion::V1_3,
+            cipher_suites: Vec::new(),
+            cert_change_action: CertChangeAction::Log,
+        },
+        Upstreams::new(addresses, LbPolicy::RoundRobin, health)?,
+        PASSWORD.to_string(),
+        Target::Default,
+        opts.clone(),
+        metrics.clone(),
+        None,
+    )?;
+    let client = Rc::new(client);
+    let client_listener = TcpListener::bind("127.0.0.1:0")?;
+    let client_addr = client_listener.local_addr()?;
+    spawn_accept(client_listener, move |mut stream, addr| {
+        mod_tcp_conn(&mut stream, &opts, Direction::Inbound);
+        let client = client.clone();
+        async move {
+            let _ = client.relay(stream, addr).await;
+        }
+    });
+
+    // Fail fast if the relay does not work at all rather than report zeros.
+    echo_once(client_addr, vec![0; 1])
+        .await
+        .context("relay through the loopback server failed")?;
+
+    let duration = Duration::from_secs(args.duration);
+    let chunk = args.chunk_size as usize;
+    println!(
+        "Measuring throughput with {} connections for {}s",
+        args.connections, args.duration
+    );
+    let (bytes, failures) = measure(args.connections, duration, move |bytes: Rc<Cell<u64>>| {
+        throughput(client_addr, chunk, bytes, Instant::now() + duration)
+    })
+    .await;
+    println!(
+        "throughput: {:.1} MiB/s each way{}",
+        bytes as f64 / duration.as_secs_f64() / (1024 * 1024) as f64,
+        failed(failures)
+    );
+
+    println!(
+        "Measuring handshakes with {} connections for {}s",
+        args.connections, args.duration
+    );
+    let (handshakes, failures) =
+        measure(args.connections, duration, move |count: Rc<Cell<u64>>| {
+            handshakes(client_addr, count, Instant::now() + duration)
+        })
+        .await;
+    println!(
+        "handshakes: {:.1}/s{}",
+        handshakes as f64 / duration.as_secs_f64(),
+        failed(failures)
+    );
+    Ok(())
+}
+
+fn failed(failures: u64) -> String {
+    match failures {
+        0 => String::new(),
+        n => format!(", {n} connections failed"),
+    }
+}
+
+/// Run connections tasks counting into one counter, returns the count and failed tasks.
+async fn measure<F, Fut>(connections: u32, duration: Duration, task: F) -> (u64, u64)
+where
+    F: Fn(Rc<Cell<u64>>) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + 'static,
+{
+    let count = Rc::new(Cell::new(0));
+    let tasks: Vec<_> = (0..connections)
+        .map(|_| monoio::spawn(task(count.clone())))
+        .collect();
+    let mut failures = 0;
+    for task in tasks {
+        // A task stuck past the deadline counts as failed.
+        if !matches!(monoio::time::timeout(duration * 2, task).await, Ok(Ok(()))) {
+            failures += 1;
+        }
+    }
+    (count.get(), failures)
+}
+
+/// Write chunks and read them back through one connection until deadline.
+async fn throughput(
+    addr: std::net::SocketAddr,
+    chunk: usize,
+    bytes: Rc<Cell<u64>>,
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    let mut stream = connect(addr).await?;
+    let mut buf = vec![0; chunk];
+    while Instant::now() < deadline {
+        let (res, b) = stream.write_all(buf).await;
+        res?;
+        let (res, b) = stream.read_exact(b).await;
+        res?;
+        buf = b;
+        bytes.set(bytes.get() + chunk as u64);
+    }
+    Ok(())
+}
+
+/// Open connections one after another until deadline, each through a new tunnel.
+async fn handshakes(
+    addr: std::net::SocketAddr,
+    count: Rc<Cell<u64>>,
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0; 1];
+    while Instant::now() < deadline {
+        buf = echo_once(addr, buf).await?;
+        count.set(count.get() + 1);
+    }
+    Ok(())
+}
+
+async fn echo_once(addr: std::net::SocketAddr, buf: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut stream = connect(addr).await?;
+    let (res, buf) = stream.write_all(buf).await;
+    res?;
+    let (res, buf) = stream.read_exact(buf).await;
+    res?;
+    Ok(buf)
+}
+
+/// Connect without nagle, so only the relay under test is subject to the --nodelay options.
+async fn connect(addr: std::net::SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn spawn_accept<F, Fut>(listener: TcpListener, handle: F)
+where
+    F: Fn(TcpStream, PeerAddr) -> Fut + 'static,
+    Fut: std::future::Future<Output = ()> + 'static,
+{
+    monoio::spawn(async move {
+        while let Ok((stream, addr)) = listener.accept().await {
+            monoio::spawn(handle(stream, PeerAddr::Tcp(addr)));
+        }
+    });
+}
+
+/// Tls server holding each connection after the handshake until the peer closes it.
+/// Without session tickets, so nothing is sent after the handshake.
+fn spawn_handshake_server() -> anyhow::Result<std::net::SocketAddr> {
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(CERT.to_vec())],
+            rustls::PrivateKey(KEY.to_vec()),
+        )?;
+    config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+    let acceptor = monoio_rustls::TlsAcceptor::from(config);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    spawn_accept(listener, move |stream, _| {
+        let acceptor = acceptor.clone();
+        async move {
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let mut buf = vec![0; 1024];
+                loop {
+                    let (res, b) = stream.read(buf).await;
+                    buf = b;
+                    if !matches!(res, Ok(n) if n > 0) {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    Ok(addr)
+}
+
+fn spawn_echo_server() -> anyhow::Result<std::net::SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    spawn_accept(listener, |mut stream, _| async move {
+        let _ = stream.set_nodelay(true);
+        let mut buf = vec![0; 65536];
+        loop {
+            let (res, b) = stream.read(buf).await;
+            let n = match res {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            };
+            let (res, b) = stream.write_all(b.slice(..n)).await;
+            if res.is_err() {
+                break;
+            }
+            buf = b.into_inner();
+        }
+    });
+    Ok(addr)
+}
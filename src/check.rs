@@ -0,0 +1,129 @@
+//! Check subcommand: validate client config and connectivity, print a report and exit.
+
+use std::{fmt::Display, sync::Arc, time::Instant};
+
+use shadow_tls::{
+    client::Probe, dns::Resolver, metrics::Metrics, upstream::Health, util::connect, Opts,
+    ShadowTlsClient,
+};
+
+use crate::ClientArgs;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CheckArgs {
+    #[clap(flatten)]
+    pub(crate) client: ClientArgs,
+    #[clap(
+        long = "tls",
+        help = "Handshake server to check directly(like cloud.tencent.com:443), port 443 of each server name by default"
+    )]
+    tls_addr: Option<String>,
+}
+
+/// Run all checks, returns whether all of them passed.
+pub fn run(args: CheckArgs, opts: Opts) -> bool {
+    match crate::block_on(&opts, check(args, opts.clone())) {
+        Ok(passed) => passed,
+        Err(e) => {
+            Report::default().fail(format!(
+                "runtime with io driver {:?} is not available: {e}",
+                opts.io_driver
+            ));
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn pass(&mut self, item: impl Display) {
+        println!("[ OK ] {item}");
+    }
+
+    fn fail(&mut self, item: impl Display) {
+        println!("[FAIL] {item}");
+        self.failed = true;
+    }
+
+    fn skip(&mut self, item: impl Display) {
+        println!("[SKIP] {item}");
+    }
+}
+
+async fn check(args: CheckArgs, opts: Opts) -> bool {
+    let mut report = Report::default();
+    let CheckArgs { client, tls_addr } = args;
+    let server_names = client.tls_names.clone();
+    let server_addrs = client.server_addrs.clone();
+    let socks5 = client.socks5;
+    let health = Arc::new(Health::new(server_addrs.clone()));
+    let client = match client.build(opts.clone(), Arc::new(Metrics::default()), None, health) {
+        Ok(client) => {
+            report.pass("config is valid");
+            client
+        }
+        Err(e) => {
+            report.fail(format!("config is invalid: {e:#}"));
+            return false;
+        }
+    };
+
+    for name in server_names.iter() {
+        let address = tls_addr.clone().unwrap_or_else(|| format!("{name}:443"));
+        let start = Instant::now();
+        match client.handshake(&address, name).await {
+            Ok(version) => report.pass(format!(
+                "handshake with {address} as {name}: {version:?} in {}ms",
+                start.elapsed().as_millis()
+            )),
+            Err(e) => report.fail(format!("handshake with {address} as {name}: {e:#}")),
+        }
+    }
+
+    for server_addr in server_addrs.iter() {
+        check_server(&mut report, &client, server_addr, socks5, &opts).await;
+    }
+
+    match report.failed {
+        true => println!("Check failed"),
+        false => println!("Check passed"),
+    }
+    !report.failed
+}
+
+/// Check a shadow-tls server is reachable and accepts the password.
+async fn check_server(
+    report: &mut Report,
+    client: &ShadowTlsClient<String>,
+    server_addr: &str,
+    socks5: bool,
+    opts: &Opts,
+) {
+    let reachable = match Resolver::new(opts.clone()).resolve(server_addr).await {
+        Ok(addrs) => connect(addrs.as_slice(), opts).await.map(drop),
+        Err(e) => Err(e),
+    };
+    match reachable {
+        Ok(()) => match client.probe_password(server_addr).await {
+            Ok(Probe::Accepted) => report.pass(format!("password accepted by {server_addr}")),
+            Ok(Probe::Rejected) => report.fail(format!(
+                "password rejected by {server_addr}, it answered as the handshake server"
+            )),
+            // A socks5 server closes the connection without a target even if password matches.
+            Ok(Probe::Closed) if socks5 => report.skip(format!(
+                "{server_addr} closed the connection, password can not be told in socks5 mode"
+            )),
+            Ok(Probe::Closed) => report.fail(format!(
+                "{server_addr} closed the connection, password is rejected or its data server is down"
+            )),
+            Err(e) => report.fail(format!("handshake through {server_addr}: {e:#}")),
+        },
+        Err(e) => report.skip(format!(
+            "{server_addr} is unreachable({e}), password is not checked"
+        )),
+    }
+}
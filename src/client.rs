@@ -1,31 +1,187 @@
-use std::net::SocketAddr;
+use std::{
+    cell::Cell,
+    fmt::Display,
+    net::SocketAddr,
+    os::unix::io::AsRawFd,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use monoio::{io::Splitable, net::TcpStream};
+use anyhow::Context;
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Split, Splitable},
+    net::TcpStream,
+};
 use monoio_rustls::TlsConnector;
-use rustls::{OwnedTrustAnchor, RootCertStore, ServerName};
+use rustls::{
+    client::{ServerCertVerifier, WebPkiVerifier},
+    OwnedTrustAnchor, ProtocolVersion, RootCertStore, ServerName,
+};
 
+#[cfg(target_os = "linux")]
+use crate::util::{dup_tcp_stream, splice_without_application_data};
 use crate::{
+    backend,
+    dns::Resolver,
+    error::RelayError,
+    limit::{Limiter, Semaphore, TotalLimits},
+    listener::PeerAddr,
+    metrics::{ByteCounter, Metrics, Relayed},
+    pool::Pool,
+    socks5::{self, Address},
     stream::HashedReadStream,
-    util::{copy_with_application_data, copy_without_application_data, mod_tcp_conn},
-    Opts,
+    upstream::Upstreams,
+    upstream_proxy::UpstreamProxy,
+    util::{
+        application_data_frame, connect, connect_until, copy_plain, copy_with_application_data,
+        copy_without_application_data, handshake_permit, mod_tcp_conn, relay_plain,
+        relay_until_expired, timeout_or_shutdown, Direction, ShutdownGuard,
+    },
+    verify::{CertChangeAction, CertWatch, NoVerifier, Pin, PinnedVerifier, RenamedVerifier},
+    Opts, Protocol,
 };
 
+const ALERT: u8 = 0x15;
+// Time to wait for the server's answer to a password probe.
+const PROBE_WAIT: Duration = Duration::from_secs(2);
+// Time to wait before pooling a connection again after failing to.
+const POOL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How server handled a password probe.
+pub enum Probe {
+    /// Kept the connection open for data.
+    Accepted,
+    /// Answered with an alert like the handshake server.
+    Rejected,
+    /// Closed the connection without an alert.
+    Closed,
+}
+
+/// Options of the tls handshake with handshake server.
+pub struct HandshakeOpts {
+    pub server_names: Vec<String>,
+    pub alpn: Vec<String>,
+    /// Accepted public key pins of the leaf certificate, any public key is accepted if empty.
+    pub pins: Vec<Pin>,
+    /// Skip the certificate chain verification, pins are still checked.
+    pub insecure: bool,
+    /// Name the certificate chain is verified for instead of the server name sent.
+    pub verify_name: Option<String>,
+    /// Tls versions offered in ClientHello.
+    pub min_version: TlsVersion,
+    pub max_version: TlsVersion,
+    /// Cipher suites offered in ClientHello in this order, rustls defaults if empty.
+    pub cipher_suites: Vec<CipherSuite>,
+    /// What to do when a server name presents another public key than its first handshake.
+    pub cert_change_action: CertChangeAction,
+}
+
+/// Tls version offered in the handshake, written as 1.2 or 1.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    V1_2,
+    V1_3,
+}
+
+impl TlsVersion {
+    fn rustls(self) -> &'static rustls::SupportedProtocolVersion {
+        match self {
+            Self::V1_2 => &rustls::version::TLS12,
+            Self::V1_3 => &rustls::version::TLS13,
+        }
+    }
+}
+
+impl FromStr for TlsVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(Self::V1_2),
+            "1.3" => Ok(Self::V1_3),
+            _ => anyhow::bail!("unsupported tls version {s:?}, expect 1.2 or 1.3"),
+        }
+    }
+}
+
+impl Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V1_2 => write!(f, "1.2"),
+            Self::V1_3 => write!(f, "1.3"),
+        }
+    }
+}
+
+/// Cipher suite offered in the handshake, written by its IANA name like
+/// TLS13_AES_128_GCM_SHA256. Only the suites implemented by rustls are available.
+#[derive(Debug, Clone, Copy)]
+pub struct CipherSuite(rustls::SupportedCipherSuite);
+
+impl FromStr for CipherSuite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        rustls::ALL_CIPHER_SUITES
+            .iter()
+            .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(s))
+            .map(|suite| Self(*suite))
+            .with_context(|| {
+                let names: Vec<_> = rustls::ALL_CIPHER_SUITES
+                    .iter()
+                    .map(|suite| format!("{:?}", suite.suite()))
+                    .collect();
+                format!(
+                    "unsupported cipher suite {s:?}, expect one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Where the server relays data of the client to, told in the first frame.
+pub enum Target {
+    /// The default data server.
+    Default,
+    /// The backend the server maps the routing tag to.
+    Tagged(String),
+    /// The socks5 target requested on in_stream.
+    Socks5,
+}
+
 /// ShadowTlsClient.
 pub struct ShadowTlsClient<A> {
     tls_connector: TlsConnector,
-    server_name: ServerName,
-    address: A,
+    /// Tls 1.2 connector of protocol v1 if the protocol is auto.
+    tls_connector_v1: Option<TlsConnector>,
+    /// Protocol tried first if auto, the one which worked last.
+    auto_protocol: Option<Cell<Protocol>>,
+    server_names: Vec<String>,
+    upstreams: Upstreams<A>,
     password: String,
-    opts: Opts,
+    target: Target,
+    resolver: Resolver,
+    pub(crate) opts: Opts,
+    metrics: Arc<Metrics>,
+    total_limits: Option<Arc<TotalLimits>>,
+    pool: Option<Pool>,
+    /// Handshakes in progress at once if capped.
+    handshake_permits: Option<Semaphore>,
+    upstream_proxy: Option<UpstreamProxy>,
+    cert_watch: CertWatch,
 }
 
 impl<A> ShadowTlsClient<A> {
     /// Create new ShadowTlsClient.
     pub fn new(
-        server_name: &str,
-        address: A,
+        handshake: HandshakeOpts,
+        upstreams: Upstreams<A>,
         password: String,
+        target: Target,
         opts: Opts,
+        metrics: Arc<Metrics>,
+        total_limits: Option<Arc<TotalLimits>>,
     ) -> anyhow::Result<Self> {
         let mut root_store = RootCertStore::empty();
         root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
@@ -35,62 +191,677 @@ impl<A> ShadowTlsClient<A> {
                 ta.name_constraints,
             )
         }));
-        // TLS 1.2 and TLS 1.3 is enabled.
-        let tls_config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        let tls_connector = TlsConnector::from(tls_config);
-        let server_name = ServerName::try_from(server_name)?;
+        let HandshakeOpts {
+            server_names,
+            alpn,
+            pins,
+            insecure,
+            verify_name,
+            min_version,
+            max_version,
+            cipher_suites,
+            cert_change_action,
+        } = handshake;
+        if min_version > max_version {
+            anyhow::bail!("min tls version {min_version} is above max tls version {max_version}");
+        }
+        // Protocol v1 tells the end of handshake by the ChangeCipherSpec of tls 1.2.
+        if opts.protocol_version != Protocol::V2 {
+            if min_version > TlsVersion::V1_2 {
+                anyhow::bail!(
+                    "protocol {} needs tls 1.2, min tls version is {min_version}",
+                    opts.protocol_version
+                );
+            }
+            if !matches!(target, Target::Default) {
+                anyhow::bail!("socks5 and backend tags need protocol v2");
+            }
+        }
+        let suites: Vec<_> = cipher_suites.into_iter().map(|s| s.0).collect();
+        let verify_name = verify_name
+            .map(|name| ServerName::try_from(name.as_str()))
+            .transpose()?;
+        let verifier: Option<Arc<dyn ServerCertVerifier>> =
+            (insecure || !pins.is_empty() || verify_name.is_some()).then(|| {
+                let mut verifier: Arc<dyn ServerCertVerifier> = match insecure {
+                    true => Arc::new(NoVerifier),
+                    false => Arc::new(WebPkiVerifier::new(root_store.clone(), None)),
+                };
+                if let (false, Some(name)) = (insecure, verify_name) {
+                    verifier = Arc::new(RenamedVerifier::new(verifier, name));
+                }
+                if !pins.is_empty() {
+                    verifier = Arc::new(PinnedVerifier::new(verifier, pins));
+                }
+                verifier
+            });
+        // Empty ALPN list means no ALPN extension in ClientHello.
+        let alpn: Vec<_> = alpn.into_iter().map(String::into_bytes).collect();
+        let connector = |max_version: TlsVersion| -> anyhow::Result<TlsConnector> {
+            let versions: Vec<_> = [TlsVersion::V1_2, TlsVersion::V1_3]
+                .into_iter()
+                .filter(|v| (min_version..=max_version).contains(v))
+                .map(TlsVersion::rustls)
+                .collect();
+            let builder = rustls::ClientConfig::builder();
+            let builder = match suites.is_empty() {
+                true => builder.with_safe_default_cipher_suites(),
+                false => builder.with_cipher_suites(&suites),
+            };
+            let mut tls_config = builder
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&versions)
+                .context("no cipher suite matches the offered tls versions")?
+                .with_root_certificates(root_store.clone())
+                .with_no_client_auth();
+            if let Some(verifier) = &verifier {
+                tls_config
+                    .dangerous()
+                    .set_certificate_verifier(verifier.clone());
+            }
+            tls_config.alpn_protocols = alpn.clone();
+            Ok(TlsConnector::from(tls_config))
+        };
+        let tls_connector = match opts.protocol_version {
+            Protocol::V1 => connector(TlsVersion::V1_2)?,
+            Protocol::V2 | Protocol::Auto => connector(max_version)?,
+        };
+        let tls_connector_v1 = match opts.protocol_version {
+            Protocol::Auto => Some(connector(TlsVersion::V1_2)?),
+            Protocol::V1 | Protocol::V2 => None,
+        };
+        if server_names.is_empty() {
+            anyhow::bail!("at least one server name is required");
+        }
+        for name in server_names.iter() {
+            ServerName::try_from(name.as_str())?;
+        }
         Ok(Self {
             tls_connector,
-            server_name,
-            address,
+            tls_connector_v1,
+            auto_protocol: (opts.protocol_version == Protocol::Auto)
+                .then(|| Cell::new(Protocol::V2)),
+            server_names,
+            upstreams,
             password,
+            target,
+            resolver: Resolver::new(opts.clone()),
+            pool: (opts.pool_size != 0).then(|| {
+                Pool::new(
+                    opts.pool_size as usize,
+                    Duration::from_secs(opts.handshake_timeout) / 2,
+                )
+            }),
+            handshake_permits: opts
+                .max_pending_handshakes
+                .map(|max| Semaphore::new(max as usize)),
+            upstream_proxy: match (&opts.upstream_proxy, &opts.upstream_socks5) {
+                (Some(_), Some(_)) => {
+                    anyhow::bail!("--upstream-proxy and --upstream-socks5 are mutually exclusive")
+                }
+                (Some(http), None) => Some(UpstreamProxy::Http(http.clone())),
+                (None, Some(socks5)) => Some(UpstreamProxy::Socks5(socks5.clone())),
+                (None, None) => None,
+            },
+            cert_watch: CertWatch::new(cert_change_action),
             opts,
+            metrics,
+            total_limits,
         })
     }
 
     /// Establish connection with remote and relay data.
-    pub async fn relay(
+    /// In socks5 mode the target is read from in_stream and sent in the first frame
+    /// for server to connect.
+    pub async fn relay<S>(
         &self,
-        mut in_stream: TcpStream,
-        in_stream_addr: SocketAddr,
-    ) -> anyhow::Result<()>
+        mut in_stream: S,
+        in_stream_addr: PeerAddr,
+    ) -> Result<Relayed, RelayError>
     where
-        A: std::net::ToSocketAddrs,
+        A: AsRef<str>,
+        S: AsyncReadRent + AsyncWriteRent + Split + AsRawFd,
     {
-        let (mut out_stream, hash) = self.connect().await?;
+        let start = Instant::now();
+        if self.opts.plain_relay {
+            let out_stream = self
+                .connect_upstream()
+                .await
+                .map_err(RelayError::UpstreamConnect)?;
+            let total_limits = self.total_limits.as_deref();
+            return relay_plain(
+                in_stream,
+                out_stream,
+                &self.opts,
+                &self.metrics,
+                total_limits,
+                start,
+            )
+            .await;
+        }
+        let in_fd = in_stream.as_raw_fd();
+        let mut guard = ShutdownGuard::default();
+        guard.watch(in_fd)?;
+        let socks5_target: Option<Address> = match self.target {
+            Target::Socks5 => Some(socks5::accept(&mut in_stream).await?),
+            _ => None,
+        };
+        let server_name = self.pick_server_name();
+        let sni = self.metrics.sni.counters(Some(server_name));
+        let (mut out_stream, hash, protocol) =
+            self.connect(&mut guard, server_name).await.map_err(|e| {
+                Metrics::inc(&self.metrics.handshake_failures);
+                if let Some(sni) = &sni {
+                    Metrics::inc(&sni.handshake_failures);
+                }
+                e
+            })?;
+        if let Some(sni) = &sni {
+            Metrics::inc(&sni.handshakes);
+        }
         let mut hash_8b = [0; 8];
         unsafe { std::ptr::copy_nonoverlapping(hash.as_ptr(), hash_8b.as_mut_ptr(), 8) };
+        // The hmac must lead the first frame, so it goes with the target if any.
+        let first_frame = match (&self.target, socks5_target) {
+            (_, Some(target)) => {
+                tracing::debug!(peer = %in_stream_addr, %target, "relay to socks5 target");
+                let mut data = hash_8b.to_vec();
+                target.encode(&mut data);
+                Some(data)
+            }
+            (Target::Tagged(tag), _) => {
+                let mut data = hash[8..16].to_vec();
+                backend::encode_tag(tag, &mut data);
+                Some(data)
+            }
+            _ => None,
+        };
+        let v1 = protocol == Protocol::V1;
+        let prefix = match first_frame {
+            // Protocol v1 sends no hmac, the target is always the default.
+            _ if v1 => None,
+            Some(data) => {
+                let (res, _) = out_stream.write_all(application_data_frame(&data)).await;
+                res?;
+                None
+            }
+            None => Some(hash_8b),
+        };
+        let fds = [in_fd, out_stream.as_raw_fd()];
         let (mut out_r, mut out_w) = out_stream.split();
         let (mut in_r, mut in_w) = in_stream.split();
-        let (a, b) = monoio::join!(
-            copy_without_application_data(&mut out_r, &mut in_w),
-            copy_with_application_data(&mut in_r, &mut out_w, Some(hash_8b))
+        let total_limits = self.total_limits.as_deref();
+        let activity = Cell::new(Instant::now());
+        let outbound_limiter = Limiter::new(
+            self.opts.rate_limit,
+            total_limits.map(|l| &l.outbound),
+            &activity,
         );
-        let (_, _) = (a?, b?);
-        tracing::info!("Relay for {in_stream_addr} finished");
-        Ok(())
+        let inbound_limiter = Limiter::new(
+            self.opts.rate_limit,
+            total_limits.map(|l| &l.inbound),
+            &activity,
+        );
+        let inbound_counter = ByteCounter::new(&self.metrics.bytes_inbound)
+            .with_sni(sni.as_ref().map(|sni| &sni.bytes_inbound));
+        let outbound_counter = ByteCounter::new(&self.metrics.bytes_outbound)
+            .with_sni(sni.as_ref().map(|sni| &sni.bytes_outbound));
+        let outbound_copy = async {
+            if v1 {
+                return copy_plain(
+                    &mut out_r,
+                    &mut in_w,
+                    self.opts.buffer_bytes(),
+                    &outbound_counter,
+                    &outbound_limiter,
+                )
+                .await;
+            }
+            // Unix sockets are not spliced, there is no tcp stream to open for them.
+            #[cfg(target_os = "linux")]
+            if self.opts.splice && matches!(in_stream_addr, PeerAddr::Tcp(_)) {
+                let mut in_w = dup_tcp_stream(in_fd)?;
+                return splice_without_application_data(
+                    &mut out_r,
+                    &mut in_w,
+                    &outbound_counter,
+                    &outbound_limiter,
+                )
+                .await
+                .map_err(hint_v1);
+            }
+            copy_without_application_data(
+                &mut out_r,
+                &mut in_w,
+                self.opts.buffer_bytes(),
+                &outbound_counter,
+                &outbound_limiter,
+            )
+            .await
+            .map_err(hint_v1)
+        };
+        let inbound_copy = async {
+            if v1 {
+                return copy_plain(
+                    &mut in_r,
+                    &mut out_w,
+                    self.opts.buffer_bytes(),
+                    &inbound_counter,
+                    &inbound_limiter,
+                )
+                .await;
+            }
+            copy_with_application_data(
+                &mut in_r,
+                &mut out_w,
+                prefix,
+                self.opts.buffer_bytes(),
+                &inbound_counter,
+                &inbound_limiter,
+            )
+            .await
+        };
+        let relay = async { monoio::join!(outbound_copy, inbound_copy) };
+        let relayed = relay_until_expired(
+            Duration::from_secs(self.opts.idle_timeout),
+            self.opts.max_connection_deadline(start),
+            &activity,
+            &fds,
+            relay,
+        )
+        .await;
+        let summary = Relayed {
+            inbound: inbound_counter.get(),
+            outbound: outbound_counter.get(),
+            duration: start.elapsed(),
+        };
+        // Handshakes of v2 with a v1 server succeed if the handshake server supports only
+        // tls 1.2, the data server answers unframed then.
+        if let (Some(auto), Ok((Err(e), _))) = (&self.auto_protocol, &relayed) {
+            if !v1 && e.kind() == std::io::ErrorKind::InvalidData {
+                auto.set(Protocol::V1);
+                tracing::warn!(
+                    "Server sent data outside tls records, using protocol v1 from now on"
+                );
+            }
+        }
+        match relayed {
+            Ok((Ok(_), Ok(_))) => Ok(summary),
+            Ok((Err(e), _)) | Ok((_, Err(e))) => Err(RelayError::Relay(summary, e.into())),
+            Err(expired) => {
+                tracing::info!(peer = %in_stream_addr, "Relay closed for {expired}");
+                Ok(summary)
+            }
+        }
     }
 
-    /// Connect remote, do handshaking and calculate HMAC.
-    async fn connect(&self) -> anyhow::Result<(TcpStream, [u8; 20])>
+    /// Take a pooled connection or connect an upstream, do handshaking and calculate HMAC.
+    /// The upstream is watched by guard from connected on.
+    /// Waits for a handshake permit first, so pooled connections do not age meanwhile.
+    /// If the protocol is auto, a failed handshake is retried once with the other protocol
+    /// through a new connection, returns the protocol handshaken with.
+    async fn connect(
+        &self,
+        guard: &mut ShutdownGuard,
+        server_name: &str,
+    ) -> Result<(TcpStream, [u8; 20], Protocol), RelayError>
     where
-        A: std::net::ToSocketAddrs,
+        A: AsRef<str>,
     {
-        let mut stream = TcpStream::connect(&self.address).await?;
-        mod_tcp_conn(&mut stream, true, self.opts.nodelay);
-        tracing::debug!("tcp connected, start handshaking");
-        let stream = HashedReadStream::new(stream, self.password.as_bytes())?;
-        let tls_stream = self
-            .tls_connector
-            .connect(self.server_name.clone(), stream)
-            .await?;
-        let (io, _) = tls_stream.into_parts();
+        let _permit = handshake_permit(self.handshake_permits.as_ref(), &self.opts, &self.metrics)
+            .await
+            .map_err(|_| RelayError::Timeout)?;
+        let stream = match self.pool.as_ref().and_then(Pool::take) {
+            Some(stream) => {
+                tracing::debug!("use pooled connection");
+                stream
+            }
+            None => self
+                .connect_upstream()
+                .await
+                .map_err(RelayError::UpstreamConnect)?,
+        };
+        let fd = stream.as_raw_fd();
+        guard.watch(fd)?;
+        let protocol = self.protocol();
+        let e = match self.handshake_through(stream, server_name, protocol).await {
+            Ok((stream, hash)) => return Ok((stream, hash, protocol)),
+            Err(e) => e,
+        };
+        // Connect failures are not for the protocol, only handshakes are retried.
+        let auto = match (&self.auto_protocol, &e) {
+            (Some(auto), RelayError::TlsHandshake(_) | RelayError::Timeout) => auto,
+            _ => return Err(e),
+        };
+        guard.release(fd);
+        let other = match protocol {
+            Protocol::V1 => Protocol::V2,
+            Protocol::V2 | Protocol::Auto => Protocol::V1,
+        };
+        tracing::warn!("Handshake with protocol {protocol} failed: {e}, retrying with {other}");
+        let stream = self
+            .connect_upstream()
+            .await
+            .map_err(RelayError::UpstreamConnect)?;
+        guard.watch(stream.as_raw_fd())?;
+        let (stream, hash) = self.handshake_through(stream, server_name, other).await?;
+        auto.set(other);
+        tracing::info!("Handshake with protocol {other} worked, using it from now on");
+        Ok((stream, hash, other))
+    }
+
+    /// Connector for handshakes with protocol.
+    fn tls_connector(&self, protocol: Protocol) -> &TlsConnector {
+        match (protocol, &self.tls_connector_v1) {
+            (Protocol::V1, Some(tls_connector)) => tls_connector,
+            _ => &self.tls_connector,
+        }
+    }
+
+    /// Protocol to handshake with first.
+    fn protocol(&self) -> Protocol {
+        match &self.auto_protocol {
+            Some(auto) => auto.get(),
+            None => self.opts.protocol_version,
+        }
+    }
+
+    /// Connect upstreams in the order picked by the load balancing policy, until one is
+    /// connected.
+    async fn connect_upstream(&self) -> anyhow::Result<TcpStream>
+    where
+        A: AsRef<str>,
+    {
+        let mut last_err = None;
+        for idx in self.upstreams.candidates(Instant::now()) {
+            let address = self.upstreams.address(idx).as_ref();
+            let connected = self.connect_server(address).await;
+            match connected {
+                Ok(stream) => {
+                    self.upstreams.record_success(idx);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    match self.upstreams.record_failure(idx, Instant::now()) {
+                        true => {
+                            tracing::warn!("Connect {address:?} failed: {e}, skip it for a while")
+                        }
+                        false => tracing::warn!("Connect {address:?} failed: {e}"),
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("upstreams are not empty").into())
+    }
+
+    /// Handshake through a connected shadow-tls server and calculate HMAC.
+    async fn handshake_through(
+        &self,
+        mut stream: TcpStream,
+        server_name: &str,
+        protocol: Protocol,
+    ) -> Result<(TcpStream, [u8; 20]), RelayError> {
+        mod_tcp_conn(&mut stream, &self.opts, Direction::Outbound);
+        let tls_connector = self.tls_connector(protocol);
+        let start = Instant::now();
+        tracing::debug!(sni = server_name, "tcp connected, start handshaking");
+        let fd = stream.as_raw_fd();
+        let stream =
+            HashedReadStream::new(stream, self.password.as_bytes()).map_err(anyhow::Error::from)?;
+        let tls_stream = timeout_or_shutdown(
+            Duration::from_secs(self.opts.handshake_timeout),
+            &[fd],
+            tls_connector.connect(
+                ServerName::try_from(server_name).map_err(anyhow::Error::from)?,
+                stream,
+            ),
+        )
+        .await
+        .map_err(|_| RelayError::Timeout)?
+        .map_err(|e| RelayError::TlsHandshake(e.into()))?;
+        Metrics::inc(&self.metrics.handshakes);
+        self.metrics.events.handshake(Some(server_name));
+        let (io, session) = tls_stream.into_parts();
+        if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+            self.cert_watch
+                .check(server_name, cert)
+                .map_err(RelayError::TlsHandshake)?;
+        }
+        tracing::debug!(
+            sni = server_name,
+            "negotiated version: {:?}, cipher suite: {:?}, alpn: {:?}",
+            session.protocol_version(),
+            session.negotiated_cipher_suite().map(|s| s.suite()),
+            session.alpn_protocol().map(String::from_utf8_lossy)
+        );
         let hash = io.hash();
-        tracing::debug!("tls handshake finished, signed hmac: {:?}", hash);
+        tracing::debug!(
+            sni = server_name,
+            duration_ms = start.elapsed().as_millis() as u64,
+            "tls handshake finished, signed hmac: {:?}",
+            hash
+        );
         let stream = io.into_inner();
         Ok((stream, hash))
     }
+
+    /// Keep the pool filled with connections made ahead of demand, returns at once if
+    /// pooling is disabled.
+    pub async fn fill_pool(&self)
+    where
+        A: AsRef<str>,
+    {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return,
+        };
+        loop {
+            pool.wanted().await;
+            let started = Instant::now();
+            // Failures are logged by connect_upstream.
+            match self.connect_upstream().await {
+                Ok(stream) => pool.put(started, stream),
+                Err(_) => monoio::time::sleep(POOL_RETRY_DELAY).await,
+            }
+        }
+    }
+
+    /// Handshake through each upstream every interval, and mark the ones failing to finish
+    /// it within timeout as unhealthy until they pass again.
+    pub async fn check_health(&self, interval: Duration, timeout: Duration)
+    where
+        A: AsRef<str>,
+    {
+        loop {
+            for idx in 0..self.upstreams.count() {
+                let address = self.upstreams.address(idx).as_ref();
+                let result = self.handshake_within(address, timeout).await;
+                let changed = self.upstreams.health().set(idx, result.is_ok());
+                match result {
+                    Err(e) if changed => {
+                        tracing::warn!("Upstream {address:?} is unhealthy: {e:#}")
+                    }
+                    Ok(()) if changed => tracing::info!("Upstream {address:?} is healthy again"),
+                    _ => tracing::debug!("Upstream {address:?} checked: {result:?}"),
+                }
+            }
+            monoio::time::sleep(interval).await;
+        }
+    }
+
+    /// Connect address and handshake through it within timeout.
+    async fn handshake_within(&self, address: &str, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let hop = self.first_hop(address);
+        let addrs = self.resolver.resolve(hop).await?;
+        let mut stream = connect_until(addrs.as_slice(), &self.opts, deadline.into()).await?;
+        self.tunnel(&mut stream, address).await?;
+        let fd = stream.as_raw_fd();
+        let server_name = ServerName::try_from(self.pick_server_name())?;
+        let mut tls_stream = timeout_or_shutdown(
+            deadline.saturating_duration_since(Instant::now()),
+            &[fd],
+            self.tls_connector(self.protocol())
+                .connect(server_name, stream),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+        Metrics::inc(&self.metrics.handshakes);
+        let _ = tls_stream.shutdown().await;
+        Ok(())
+    }
+
+    /// Handshake with a tls server directly, returns the negotiated protocol version.
+    pub async fn handshake(
+        &self,
+        address: &str,
+        server_name: &str,
+    ) -> anyhow::Result<ProtocolVersion> {
+        let hop = self.first_hop(address);
+        let addrs = self.resolver.resolve(hop).await?;
+        let mut stream = connect(addrs.as_slice(), &self.opts).await?;
+        self.tunnel(&mut stream, address).await?;
+        let fd = stream.as_raw_fd();
+        let tls_stream = timeout_or_shutdown(
+            Duration::from_secs(self.opts.handshake_timeout),
+            &[fd],
+            self.tls_connector
+                .connect(ServerName::try_from(server_name)?, stream),
+        )
+        .await??;
+        let (_, session) = tls_stream.into_parts();
+        session
+            .protocol_version()
+            .context("no protocol version negotiated")
+    }
+
+    /// Handshake through server at address and send the hmac alone to see how server
+    /// handles it. A server rejecting the hmac relays it to the handshake server, which
+    /// answers with an alert or closes, while an accepting one connects its data server and
+    /// waits for more.
+    pub async fn probe_password(&self, address: &str) -> anyhow::Result<Probe> {
+        let stream = self.connect_server(address).await?;
+        let (mut stream, hash) = self
+            .handshake_through(stream, self.pick_server_name(), self.protocol())
+            .await?;
+        let (res, _) = stream.write_all(application_data_frame(&hash[..8])).await;
+        res?;
+        let fd = stream.as_raw_fd();
+        let mut received = Vec::new();
+        let answered = timeout_or_shutdown(PROBE_WAIT, &[fd], async {
+            let mut buf = vec![0; 4096];
+            loop {
+                let (res, b) = stream.read(buf).await;
+                match res {
+                    Ok(n) if n > 0 => received.extend_from_slice(&b[..n]),
+                    _ => break,
+                }
+                if contains_alert(&received) {
+                    break;
+                }
+                buf = b;
+            }
+        })
+        .await;
+        Ok(match answered {
+            Err(_) => Probe::Accepted,
+            Ok(_) if contains_alert(&received) => Probe::Rejected,
+            Ok(_) => Probe::Closed,
+        })
+    }
+
+    /// Connect the server at address with retries, through the upstream proxy if any.
+    async fn connect_server(&self, address: &str) -> std::io::Result<TcpStream> {
+        let hop = self.first_hop(address);
+        let addrs = self.resolver.resolve(hop).await?;
+        let mut stream = self.connect_with_retries(hop, addrs.as_slice()).await?;
+        self.tunnel(&mut stream, address).await?;
+        Ok(stream)
+    }
+
+    /// Address connected first to reach address: the upstream proxy if any.
+    fn first_hop<'a>(&'a self, address: &'a str) -> &'a str {
+        match &self.upstream_proxy {
+            Some(proxy) => proxy.address(),
+            None => address,
+        }
+    }
+
+    /// Tunnel stream connected to the upstream proxy if any to address, within
+    /// connect_timeout.
+    async fn tunnel(&self, stream: &mut TcpStream, address: &str) -> std::io::Result<()> {
+        let proxy = match &self.upstream_proxy {
+            Some(proxy) => proxy,
+            None => return Ok(()),
+        };
+        let fd = stream.as_raw_fd();
+        timeout_or_shutdown(
+            Duration::from_secs(self.opts.connect_timeout),
+            &[fd],
+            proxy.connect(stream, address),
+        )
+        .await?
+        .map_err(|e| std::io::Error::new(e.kind(), format!("proxy {proxy}: {e}")))?;
+        tracing::debug!("tunneled to {address:?} through {proxy}");
+        Ok(())
+    }
+
+    /// Connect server, retrying connect_retries times with backoff doubled after each
+    /// retry. All retries share one connect_timeout.
+    async fn connect_with_retries(
+        &self,
+        address: &str,
+        addrs: &[SocketAddr],
+    ) -> std::io::Result<TcpStream> {
+        let deadline = Instant::now() + Duration::from_secs(self.opts.connect_timeout);
+        let mut backoff = Duration::from_millis(self.opts.retry_backoff);
+        let mut retries = 0;
+        loop {
+            let err = match connect_until(addrs, &self.opts, deadline.into()).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            };
+            if retries == self.opts.connect_retries || Instant::now() + backoff >= deadline {
+                return Err(err);
+            }
+            retries += 1;
+            tracing::warn!(
+                "Connect {address:?} failed: {err}, retry {retries}/{} in {backoff:?}",
+                self.opts.connect_retries
+            );
+            monoio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    /// Pick a server name randomly for each connection.
+    fn pick_server_name(&self) -> &str {
+        let idx = monoio::utils::thread_rng_n(self.server_names.len() as u32) as usize;
+        &self.server_names[idx]
+    }
+}
+
+/// Explain data from the server outside tls records, a protocol v1 server relays its data
+/// server unframed.
+fn hint_v1(e: std::io::Error) -> std::io::Error {
+    match e.kind() {
+        std::io::ErrorKind::InvalidData => std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{e}, the server may run protocol v1"),
+        ),
+        _ => e,
+    }
+}
+
+/// Whether the tls records contain an alert.
+fn contains_alert(mut records: &[u8]) -> bool {
+    while records.len() >= 5 {
+        if records[0] == ALERT {
+            return true;
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        records = &records[(5 + len).min(records.len())..];
+    }
+    false
 }
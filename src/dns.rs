@@ -0,0 +1,295 @@
+//! Name resolution through the system resolver, or a custom dns server over tcp.
+//! Monoio has no udp socket, dns over tcp(RFC 7766) is used instead, which is also
+//! not affected by injected udp responses.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    os::unix::io::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use monoio::io::{AsyncReadRentExt, AsyncWriteRentExt};
+
+use crate::{
+    util::{connect, timeout_or_shutdown},
+    Opts,
+};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Resolver with a cache of resolved hosts.
+/// Entries live for dns_cache_ttl(or the record ttl if smaller), and expired entries are
+/// still used within dns_cache_grace if resolving fails.
+/// Only configured hosts are cached, hosts chosen by clients go through `lookup_host`.
+pub struct Resolver {
+    opts: Opts,
+    cache: RefCell<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl Resolver {
+    pub fn new(opts: Opts) -> Self {
+        Self {
+            opts,
+            cache: Default::default(),
+        }
+    }
+
+    /// Resolve addr like `host:port`.
+    pub async fn resolve(&self, addr: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        let (host, port) = addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid address"))?;
+        self.resolve_host(host, port).await
+    }
+
+    pub async fn resolve_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let now = Instant::now();
+        let cached = self.cache.borrow().get(host).cloned();
+        let ips = match cached {
+            Some((ips, expire)) if expire > now => ips,
+            cached => match self.lookup(host).await {
+                Ok((ips, ttl)) => {
+                    let ttl = Duration::from_secs(ttl.min(self.opts.dns_cache_ttl));
+                    if !ttl.is_zero() {
+                        self.cache
+                            .borrow_mut()
+                            .insert(host.to_string(), (ips.clone(), now + ttl));
+                    }
+                    ips
+                }
+                Err(e) => match cached {
+                    Some((ips, expire))
+                        if now < expire + Duration::from_secs(self.opts.dns_cache_grace) =>
+                    {
+                        tracing::warn!("{e}, use expired addresses of {host}");
+                        ips
+                    }
+                    _ => return Err(e),
+                },
+            },
+        };
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Resolve host without the cache, for hosts chosen by clients that would grow it
+    /// without bound.
+    pub async fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let (ips, _) = self.lookup(host).await?;
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Resolve host with the system resolver or the custom dns server, returns addresses
+    /// with their ttl in seconds.
+    async fn lookup(&self, host: &str) -> io::Result<(Vec<IpAddr>, u64)> {
+        let server = match self.opts.dns {
+            Some(server) => server,
+            None => {
+                let ips = (host, 0).to_socket_addrs()?.map(|a| a.ip()).collect();
+                return Ok((ips, u64::MAX));
+            }
+        };
+        let (ips, ttl) = self.query(server, host).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("resolve {host} with {server} failed: {e}"),
+            )
+        })?;
+        tracing::debug!("resolved {host} to {ips:?} with ttl {ttl}s");
+        Ok((ips, ttl as u64))
+    }
+
+    /// Query A and AAAA records in one connection, returns addresses with the min ttl.
+    async fn query(&self, server: SocketAddr, host: &str) -> io::Result<(Vec<IpAddr>, u32)> {
+        let mut queries = Vec::with_capacity(2);
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            let id = monoio::utils::thread_rng_n(u16::MAX as u32) as u16;
+            queries.push((id, build_query(id, host, qtype)?));
+        }
+        let mut stream = connect([server].as_slice(), &self.opts).await?;
+        let fd = stream.as_raw_fd();
+        timeout_or_shutdown(
+            Duration::from_secs(self.opts.connect_timeout),
+            &[fd],
+            async {
+                let mut data = Vec::new();
+                for (_, query) in queries.iter() {
+                    data.extend_from_slice(&(query.len() as u16).to_be_bytes());
+                    data.extend_from_slice(query);
+                }
+                let (res, _) = stream.write_all(data).await;
+                res?;
+                let mut ips = Vec::new();
+                let mut ttl = u32::MAX;
+                // Responses may come in any order.
+                for _ in queries.iter() {
+                    let (res, len) = stream.read_exact(vec![0; 2]).await;
+                    res?;
+                    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                    let (res, response) = stream.read_exact(vec![0; len]).await;
+                    res?;
+                    let (answers, min_ttl) = parse_response(&response, &queries)?;
+                    ips.extend(answers);
+                    ttl = ttl.min(min_ttl);
+                }
+                if ips.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "no address"));
+                }
+                Ok((ips, ttl))
+            },
+        )
+        .await?
+    }
+}
+
+/// Build a query for host, which must be a valid domain name.
+fn build_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid host name {host:?}: {reason}"),
+        )
+    };
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.len() > 253 {
+        return Err(invalid("longer than 253 bytes"));
+    }
+    // Header with recursion desired and one question.
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        match label.len() {
+            0 => return Err(invalid("empty label")),
+            len if len > 63 => return Err(invalid("label longer than 63 bytes")),
+            _ => (),
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Parse a response to one of the queries, returns addresses with the min ttl.
+fn parse_response(buf: &[u8], queries: &[(u16, Vec<u8>)]) -> io::Result<(Vec<IpAddr>, u32)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid dns response");
+    let u16_at = |offset: usize| -> io::Result<u16> {
+        let b = buf.get(offset..offset + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    };
+    let id = u16_at(0)?;
+    if !queries.iter().any(|(qid, _)| *qid == id) {
+        return Err(invalid());
+    }
+    let rcode = u16_at(2)? & 0x0f;
+    if rcode == RCODE_NXDOMAIN {
+        return Ok((Vec::new(), u32::MAX));
+    }
+    if rcode != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("dns server returned rcode {rcode}"),
+        ));
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(buf, offset).ok_or_else(invalid)? + 4;
+    }
+    let mut ips = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        offset = skip_name(buf, offset).ok_or_else(invalid)?;
+        let (rtype, class) = (u16_at(offset)?, u16_at(offset + 2)?);
+        let record_ttl = (u16_at(offset + 4)? as u32) << 16 | u16_at(offset + 6)? as u32;
+        let len = u16_at(offset + 8)? as usize;
+        let data = buf
+            .get(offset + 10..offset + 10 + len)
+            .ok_or_else(invalid)?;
+        offset += 10 + len;
+        let ip = match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap()))
+            }
+            // CNAME and others are skipped, recursive servers answer the final records too.
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(record_ttl);
+    }
+    Ok((ips, ttl))
+}
+
+/// Returns offset after the name at offset.
+fn skip_name(buf: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            // Compression pointer ends the name.
+            l if l & 0xc0 == 0xc0 => return Some(offset + 2),
+            l => offset += 1 + l,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let query = build_query(0x1234, "example.com", TYPE_A).unwrap();
+        let mut response = query.clone();
+        // QR and RA set, two answers.
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 2]);
+        // CNAME to a name which is skipped, then an A record, both with name pointers.
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 10, 0, 2, 0xc0, 12]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 1, 2, 3, 4]);
+        let queries = [(0x1234, query)];
+        let (ips, ttl) = parse_response(&response, &queries).unwrap();
+        assert_eq!(ips, vec![IpAddr::from([1, 2, 3, 4])]);
+        assert_eq!(ttl, 30);
+        assert!(parse_response(&response[..response.len() - 1], &queries).is_err());
+    }
+
+    #[test]
+    fn test_build_query() {
+        assert_eq!(
+            build_query(1, "example.com.", TYPE_A).unwrap(),
+            build_query(1, "example.com", TYPE_A).unwrap()
+        );
+        let label = "a".repeat(63);
+        assert!(build_query(1, &format!("{label}.com"), TYPE_A).is_ok());
+        assert!(build_query(1, &format!("{label}a.com"), TYPE_A).is_err());
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+        assert!(build_query(1, "", TYPE_A).is_err());
+        assert!(build_query(1, &[label.as_str(); 4].join("."), TYPE_A).is_err());
+    }
+}
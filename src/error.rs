@@ -0,0 +1,83 @@
+//! Categorized relay failures, so logs tell wrong passwords from a dead handshake server.
+
+use std::fmt::Display;
+
+use crate::{listener::PeerAddr, metrics::Relayed};
+
+#[derive(Debug)]
+pub enum RelayError {
+    /// The client hmac matched no password, the connection was relayed to the handshake
+    /// server like a probe.
+    BadPassword,
+    /// The tls handshake failed or was not a valid one.
+    TlsHandshake(anyhow::Error),
+    /// Connecting the shadow-tls server, handshake server or data server failed.
+    UpstreamConnect(anyhow::Error),
+    /// The handshake did not finish within handshake_timeout.
+    Timeout,
+    /// Relaying failed after the handshake, like a connection reset, with the bytes
+    /// relayed before.
+    Relay(Relayed, anyhow::Error),
+    /// Anything else.
+    Other(anyhow::Error),
+}
+
+impl RelayError {
+    /// Category logged as a structured field.
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::BadPassword => "bad_password",
+            Self::TlsHandshake(_) => "tls_handshake",
+            Self::UpstreamConnect(_) => "upstream_connect",
+            Self::Timeout => "timeout",
+            Self::Relay(..) => "relay",
+            Self::Other(_) => "other",
+        }
+    }
+
+    /// Log the failure of a connection from peer. Relay errors are logged with the summary
+    /// like a finished relay, and other errors are routine and only logged at debug level.
+    pub fn log(&self, peer: &PeerAddr) {
+        match self {
+            Self::Relay(relayed, e) => tracing::info!(
+                %peer,
+                inbound = relayed.inbound,
+                outbound = relayed.outbound,
+                duration_ms = relayed.duration.as_millis() as u64,
+                error = %format_args!("{e:#}"),
+                "Relay finished with error"
+            ),
+            Self::Other(_) => {
+                tracing::debug!(%peer, category = self.category(), error = %self, "Relay failed")
+            }
+            _ => tracing::warn!(%peer, category = self.category(), error = %self, "Relay failed"),
+        }
+    }
+}
+
+impl Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadPassword => write!(f, "hmac matches no password"),
+            Self::TlsHandshake(e) => write!(f, "tls handshake failed: {e:#}"),
+            Self::UpstreamConnect(e) => write!(f, "connect failed: {e:#}"),
+            Self::Timeout => write!(f, "handshake timed out"),
+            Self::Relay(_, e) => write!(f, "relay failed: {e:#}"),
+            Self::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+impl From<anyhow::Error> for RelayError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Other(e)
+    }
+}
+
+impl From<std::io::Error> for RelayError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Other(e.into())
+    }
+}
@@ -0,0 +1,247 @@
+//! Connection events streamed over --event-socket.
+
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    future::Future,
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::RelayError,
+    listener::PeerAddr,
+    metrics::{Metrics, Relayed},
+};
+
+/// Events a subscriber may lag behind before it is dropped, so a slow subscriber never
+/// holds up relays.
+const QUEUE_SIZE: usize = 1024;
+
+thread_local! {
+    /// Connection whose relay is being polled, with id 0 if none.
+    static CURRENT: RefCell<Connection> = RefCell::new(Connection::default());
+}
+
+#[derive(Default)]
+struct Connection {
+    id: u64,
+    /// Sni of the finished handshake.
+    sni: Option<String>,
+}
+
+/// Connection events broadcast to subscribers of --event-socket as newline delimited json.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<SyncSender<Arc<str>>>>,
+    // Checked first so events are not formatted when nobody listens.
+    count: AtomicUsize,
+}
+
+impl Events {
+    /// Receive events published from now on.
+    pub fn subscribe(&self) -> Receiver<Arc<str>> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(tx);
+        self.count.store(subscribers.len(), Ordering::Relaxed);
+        rx
+    }
+
+    /// Send an event to subscribers, dropping the ones which are gone or lag behind.
+    fn publish(&self, id: u64, event: &str, fields: impl FnOnce(&mut String)) {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let mut line = format!(r#"{{"event":"{event}","id":{id},"time_ms":{time_ms}"#);
+        fields(&mut line);
+        line.push_str("}\n");
+        let line: Arc<str> = line.into();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| match tx.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("Dropped an event subscriber lagging {QUEUE_SIZE} events behind");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        self.count.store(subscribers.len(), Ordering::Relaxed);
+    }
+
+    pub fn accepted(&self, id: u64, peer: &PeerAddr) {
+        self.publish(id, "accepted", |line| {
+            let _ = write!(line, r#","peer":{}"#, quote(&peer.to_string()));
+        });
+    }
+
+    /// A handshake of the connection being polled finished, ignored outside connections.
+    pub fn handshake(&self, sni: Option<&str>) {
+        let id = CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            current.sni = sni.map(str::to_owned);
+            current.id
+        });
+        if id == 0 {
+            return;
+        }
+        self.publish(id, "handshake", |line| {
+            if let Some(sni) = sni {
+                let _ = write!(line, r#","sni":{}"#, quote(sni));
+            }
+        });
+    }
+
+    pub fn closed(&self, id: u64, peer: &PeerAddr, result: &Result<Relayed, RelayError>) {
+        self.publish(id, "closed", |line| {
+            let _ = write!(line, r#","peer":{}"#, quote(&peer.to_string()));
+            let relayed = match result {
+                Ok(relayed) | Err(RelayError::Relay(relayed, _)) => Some(relayed),
+                Err(_) => None,
+            };
+            if let Some(relayed) = relayed {
+                let _ = write!(
+                    line,
+                    r#","inbound":{},"outbound":{},"duration_ms":{}"#,
+                    relayed.inbound,
+                    relayed.outbound,
+                    relayed.duration.as_millis()
+                );
+            }
+            if let Err(e) = result {
+                let _ = write!(
+                    line,
+                    r#","category":"{}","error":{}"#,
+                    e.category(),
+                    quote(&e.to_string())
+                );
+            }
+        });
+    }
+}
+
+/// Json string literal of s.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pin_project_lite::pin_project! {
+    /// Future of the relay of connection id, handshakes while polling it are published
+    /// with the id. Resolved with the sni of the handshake too.
+    pub struct InConnection<F> {
+        conn: Connection,
+        #[pin]
+        inner: F,
+    }
+}
+
+impl<F> InConnection<F> {
+    pub fn new(id: u64, inner: F) -> Self {
+        Self {
+            conn: Connection { id, sni: None },
+            inner,
+        }
+    }
+}
+
+impl<F: Future> Future for InConnection<F> {
+    type Output = (F::Output, Option<String>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        CURRENT.with(|c| std::mem::swap(&mut *c.borrow_mut(), this.conn));
+        let res = this.inner.poll(cx);
+        CURRENT.with(|c| std::mem::swap(&mut *c.borrow_mut(), this.conn));
+        res.map(|output| (output, this.conn.sni.take()))
+    }
+}
+
+/// Stream events to each client connected to listener, blocking the calling thread.
+pub fn serve(listener: UnixListener, metrics: Arc<Metrics>) {
+    for conn in listener.incoming() {
+        match conn {
+            Ok(conn) => {
+                let rx = metrics.events.subscribe();
+                std::thread::spawn(move || forward(conn, rx));
+            }
+            Err(e) => tracing::error!("Accept event subscriber failed: {e}"),
+        }
+    }
+}
+
+/// Write events to conn until it is closed, or the subscriber is dropped for lagging.
+fn forward(mut conn: UnixStream, rx: Receiver<Arc<str>>) {
+    for line in rx {
+        if conn.write_all(line.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let events = Events::default();
+        let peer = PeerAddr::Tcp("127.0.0.1:1234".parse().unwrap());
+        // Nothing is formatted without subscribers.
+        events.accepted(1, &peer);
+        let rx = events.subscribe();
+        events.accepted(2, &peer);
+        let line = rx.try_recv().unwrap();
+        assert!(line.starts_with(r#"{"event":"accepted","id":2,"time_ms":"#));
+        assert!(line.ends_with(",\"peer\":\"127.0.0.1:1234\"}\n"));
+        assert!(rx.try_recv().is_err());
+
+        // Handshakes are published only inside a connection.
+        events.handshake(Some("a.com"));
+        assert!(rx.try_recv().is_err());
+        let fut = InConnection::new(3, async { events.handshake(Some("a\"b")) });
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .build()
+            .unwrap();
+        assert_eq!(rt.block_on(fut).1.as_deref(), Some("a\"b"));
+        assert!(rx.try_recv().unwrap().ends_with(",\"sni\":\"a\\\"b\"}\n"));
+
+        events.closed(3, &peer, &Err(RelayError::BadPassword));
+        let line = rx.try_recv().unwrap();
+        assert!(line
+            .ends_with(",\"category\":\"bad_password\",\"error\":\"hmac matches no password\"}\n"));
+
+        // Lagging subscribers are dropped, the gone ones too.
+        let _lagging = events.subscribe();
+        for _ in 0..QUEUE_SIZE {
+            events.accepted(4, &peer);
+        }
+        drop(rx);
+        events.accepted(5, &peer);
+        assert_eq!(events.count.load(Ordering::Relaxed), 0);
+    }
+}
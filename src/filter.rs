@@ -0,0 +1,141 @@
+//! Peer address filtering of accepted connections.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::listener::PeerAddr;
+
+/// Cidr is an ip network like `10.0.0.0/8`, a bare ip means a single address.
+/// IPv4-mapped IPv6 peers are matched as IPv4 ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    v4: bool,
+    network: u128,
+    mask: u128,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (v4, ip) = to_u128(ip);
+        v4 == self.v4 && (ip ^ self.network) & self.mask == 0
+    }
+}
+
+/// Returns whether ip is IPv4, and its bits aligned to the high end.
+fn to_u128(ip: IpAddr) -> (bool, u128) {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    };
+    match ip {
+        IpAddr::V4(ip) => (true, (u32::from(ip) as u128) << 96),
+        IpAddr::V6(ip) => (false, u128::from(ip)),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>()?, Some(prefix.parse::<u32>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let bits = match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            anyhow::bail!("prefix length of {s} is larger than {bits}");
+        }
+        let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+        let (v4, network) = to_u128(ip);
+        Ok(Self {
+            v4,
+            network: network & mask,
+            mask,
+        })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = self.mask.count_ones();
+        match self.v4 {
+            true => write!(
+                f,
+                "{}/{}",
+                Ipv4Addr::from((self.network >> 96) as u32),
+                prefix
+            ),
+            false => write!(f, "{}/{}", Ipv6Addr::from(self.network), prefix),
+        }
+    }
+}
+
+/// IpFilter accepts peers in any allowed network, or all peers if none is allowed, unless
+/// they are in a denied network. Unix socket peers are always accepted.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn permits(&self, addr: &PeerAddr) -> bool {
+        let ip = match addr {
+            PeerAddr::Tcp(addr) => addr.ip(),
+            PeerAddr::Unix => return true,
+        };
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_filter() {
+        let cidrs = |s: &str| -> Vec<Cidr> { s.split(',').map(|c| c.parse().unwrap()).collect() };
+        let peer = |s: &str| PeerAddr::Tcp(s.parse().unwrap());
+        let filter = IpFilter::new(cidrs("10.0.0.0/8,2001:db8::/32"), cidrs("10.1.0.0/16"));
+        assert!(filter.permits(&peer("10.2.3.4:1")));
+        assert!(filter.permits(&peer("[::ffff:10.2.3.4]:1")));
+        assert!(filter.permits(&peer("[2001:db8::1]:1")));
+        assert!(!filter.permits(&peer("10.1.3.4:1")));
+        assert!(!filter.permits(&peer("11.0.0.1:1")));
+        assert!(filter.permits(&PeerAddr::Unix));
+
+        // IPv6 networks never match IPv4 peers.
+        let filter = IpFilter::new(Vec::new(), cidrs("192.168.1.1,::/0"));
+        assert!(filter.permits(&peer("192.168.1.2:1")));
+        assert!(!filter.permits(&peer("192.168.1.1:1")));
+        assert!(!filter.permits(&peer("[::ffff:192.168.1.1]:1")));
+        assert!(!filter.permits(&peer("[::1]:1")));
+
+        assert_eq!(
+            "10.1.2.3/8".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "0.0.0.0/0".parse::<Cidr>().unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Bandwidth limiting of relayed data, and concurrency limiting of handshakes.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Limiter of one relay direction, both the connection limit and the shared total limit
+/// apply if set, so the tighter one wins.
+/// Time of the last acquire is recorded in activity, which may be shared by both
+/// directions to tell an idle connection.
+pub struct Limiter<'a> {
+    conn: Option<RateLimiter>,
+    total: Option<&'a SharedRateLimiter>,
+    activity: &'a Cell<Instant>,
+}
+
+impl<'a> Limiter<'a> {
+    pub fn new(
+        conn_rate: Option<u64>,
+        total: Option<&'a SharedRateLimiter>,
+        activity: &'a Cell<Instant>,
+    ) -> Self {
+        Self {
+            conn: conn_rate.map(RateLimiter::new),
+            total,
+            activity,
+        }
+    }
+
+    /// Take n bytes of tokens from all limits, and sleep until the debts are paid.
+    pub async fn acquire(&self, n: usize) {
+        let now = Instant::now();
+        self.activity.set(now);
+        let conn = self.conn.as_ref().map(|l| l.take(n, now));
+        let total = self.total.map(|l| l.take(n, now));
+        let wait = conn.max(total).unwrap_or_default();
+        if !wait.is_zero() {
+            monoio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// TotalLimits are the limits of inbound and outbound data shared by all connections.
+pub struct TotalLimits {
+    pub inbound: SharedRateLimiter,
+    pub outbound: SharedRateLimiter,
+}
+
+impl TotalLimits {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            inbound: SharedRateLimiter::new(rate),
+            outbound: SharedRateLimiter::new(rate),
+        }
+    }
+}
+
+/// RateLimiter is a token bucket of bytes refilled at rate bytes per second, holding at most
+/// one second of tokens. The bucket may go into debt, so a large read is never split but
+/// the next one waits longer.
+pub struct RateLimiter {
+    rate: u64,
+    tokens: Cell<f64>,
+    last: Cell<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: Cell::new(rate as f64),
+            last: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Take n bytes of tokens at now, returns the time to pay the debt.
+    fn take(&self, n: usize, now: Instant) -> Duration {
+        let rate = self.rate as f64;
+        let refill = now.saturating_duration_since(self.last.get()).as_secs_f64() * rate;
+        let tokens = (self.tokens.get() + refill).min(rate) - n as f64;
+        self.tokens.set(tokens);
+        self.last.set(now);
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// SharedRateLimiter is the thread safe version of RateLimiter shared by all connections.
+/// Instead of tokens it keeps the time when the bucket turns full again, so taking tokens
+/// is a single atomic update without locks.
+pub struct SharedRateLimiter {
+    rate: u64,
+    start: Instant,
+    /// Nanoseconds since start.
+    full_at: AtomicU64,
+}
+
+impl SharedRateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Take n bytes of tokens at now, returns the time to pay the debt.
+    fn take(&self, n: usize, now: Instant) -> Duration {
+        let now = now.saturating_duration_since(self.start).as_nanos() as u64;
+        let cost = (n as u128 * NANOS_PER_SEC as u128 / self.rate as u128) as u64;
+        // A bucket full before now holds no more than one second of tokens.
+        let full_at = self
+            .full_at
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |full_at| {
+                Some(full_at.max(now) + cost)
+            })
+            .unwrap();
+        let full_at = full_at.max(now) + cost;
+        // Tokens are in debt if the bucket can not get full in one second.
+        Duration::from_nanos(full_at.saturating_sub(now + NANOS_PER_SEC))
+    }
+}
+
+/// Semaphore of one worker thread, waiters are woken in the order they came.
+pub struct Semaphore {
+    permits: Cell<usize>,
+    waiters: RefCell<VecDeque<(u64, Waker)>>,
+    next_id: Cell<u64>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits: Cell::new(permits),
+            waiters: RefCell::new(VecDeque::new()),
+            next_id: Cell::new(0),
+        }
+    }
+
+    /// Wait for a permit, which is given back when dropped.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            id: None,
+            acquired: false,
+        }
+    }
+
+    fn wake_next(&self) {
+        if self.permits.get() != 0 {
+            if let Some((_, waker)) = self.waiters.borrow_mut().pop_front() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Remove a waiter, returns whether it was still queued rather than woken.
+    fn dequeue(&self, id: u64) -> bool {
+        let mut waiters = self.waiters.borrow_mut();
+        match waiters.iter().position(|(i, _)| *i == id) {
+            Some(pos) => {
+                waiters.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    /// Set once it had to wait.
+    id: Option<u64>,
+    acquired: bool,
+}
+
+impl Acquire<'_> {
+    /// Whether it had to wait for a permit.
+    pub fn queued(&self) -> bool {
+        self.id.is_some()
+    }
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = semaphore.permits.get();
+        if permits != 0 {
+            if let Some(id) = self.id {
+                semaphore.dequeue(id);
+            }
+            semaphore.permits.set(permits - 1);
+            self.acquired = true;
+            return Poll::Ready(Permit(semaphore));
+        }
+        let mut waiters = semaphore.waiters.borrow_mut();
+        match self.id {
+            None => {
+                let id = semaphore.next_id.get();
+                semaphore.next_id.set(id + 1);
+                self.id = Some(id);
+                waiters.push_back((id, cx.waker().clone()));
+            }
+            Some(id) => match waiters.iter_mut().find(|(i, _)| *i == id) {
+                Some((_, waker)) => *waker = cx.waker().clone(),
+                // Woken but beaten to the permit by a newcomer, keep its turn.
+                None => waiters.push_front((id, cx.waker().clone())),
+            },
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    /// A waiter dropped after being woken, like on timeout, passes the wake on.
+    fn drop(&mut self) {
+        if let (Some(id), false) = (self.id, self.acquired) {
+            if !self.semaphore.dequeue(id) {
+                self.semaphore.wake_next();
+            }
+        }
+    }
+}
+
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.permits.set(self.0.permits.get() + 1);
+        self.0.wake_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1000);
+        let now = limiter.last.get();
+        // The bucket starts full.
+        assert_eq!(limiter.take(1000, now), Duration::ZERO);
+        assert_eq!(limiter.take(500, now), Duration::from_millis(500));
+        // The debt is paid after 500ms, and the next 100 bytes need 100ms more.
+        let now = now + Duration::from_millis(500);
+        assert_eq!(limiter.take(100, now), Duration::from_millis(100));
+        // Idle time refills at most one second of tokens.
+        let now = now + Duration::from_secs(10);
+        assert_eq!(limiter.take(1000, now), Duration::ZERO);
+        assert_eq!(limiter.take(1, now), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_shared_rate_limiter() {
+        let limiter = SharedRateLimiter::new(1000);
+        let now = limiter.start;
+        assert_eq!(limiter.take(1000, now), Duration::ZERO);
+        assert_eq!(limiter.take(500, now), Duration::from_millis(500));
+        let now = now + Duration::from_millis(500);
+        assert_eq!(limiter.take(100, now), Duration::from_millis(100));
+        let now = now + Duration::from_secs(10);
+        assert_eq!(limiter.take(1000, now), Duration::ZERO);
+        assert_eq!(limiter.take(1, now), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_semaphore() {
+        use std::{
+            sync::{atomic::AtomicUsize, Arc},
+            task::Wake,
+        };
+
+        struct Counter(AtomicUsize);
+        impl Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        fn poll<'a>(fut: Pin<&mut Acquire<'a>>, counter: &Arc<Counter>) -> Option<Permit<'a>> {
+            let waker = Waker::from(counter.clone());
+            match fut.poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(permit) => Some(permit),
+                Poll::Pending => None,
+            }
+        }
+        let wakes = |counter: &Arc<Counter>| counter.0.load(Ordering::Relaxed);
+
+        let semaphore = Semaphore::new(1);
+        let (a, b, c) = (
+            Arc::new(Counter(AtomicUsize::new(0))),
+            Arc::new(Counter(AtomicUsize::new(0))),
+            Arc::new(Counter(AtomicUsize::new(0))),
+        );
+        let mut first = Box::pin(semaphore.acquire());
+        let permit = poll(first.as_mut(), &a).unwrap();
+        assert!(!first.queued());
+        let mut second = Box::pin(semaphore.acquire());
+        let mut third = Box::pin(semaphore.acquire());
+        assert!(poll(second.as_mut(), &b).is_none());
+        assert!(poll(third.as_mut(), &c).is_none());
+        assert!(second.queued());
+        // Released permit wakes the first waiter only.
+        drop(permit);
+        assert_eq!((wakes(&b), wakes(&c)), (1, 0));
+        // Woken waiter gives up, the wake passes on.
+        drop(second);
+        assert_eq!(wakes(&c), 1);
+        assert!(poll(third.as_mut(), &c).is_some());
+    }
+}
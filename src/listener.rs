@@ -0,0 +1,386 @@
+//! Listeners on tcp addresses, unix socket paths, linux abstract unix sockets or sockets
+//! inherited from systemd.
+
+use std::{
+    fmt::Display,
+    io::Write,
+    net::{SocketAddr, ToSocketAddrs},
+    os::unix::{
+        fs::FileTypeExt,
+        io::{BorrowedFd, FromRawFd, IntoRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use monoio::{
+    io::AsyncReadRentExt,
+    net::{ListenerConfig, TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::{util::set_buffer_sizes, Opts};
+
+const UNIX_PREFIX: &str = "unix:";
+/// Prefix of a linux abstract socket name after UNIX_PREFIX.
+const ABSTRACT_PREFIX: char = '@';
+
+/// How an accept error is handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AcceptError {
+    /// The pending connection failed, accept the next one at once.
+    Connection,
+    /// Out of fds or memory, retry after a backoff for resources to be released.
+    Resource,
+    /// The listener is broken, stop accepting on it.
+    Fatal,
+}
+
+impl AcceptError {
+    pub fn classify(e: &std::io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => Self::Resource,
+            Some(
+                libc::ECONNABORTED
+                | libc::ECONNRESET
+                | libc::EPROTO
+                | libc::EPERM
+                | libc::ENOTCONN
+                | libc::ETIMEDOUT
+                | libc::EINTR
+                | libc::EAGAIN,
+            ) => Self::Connection,
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// Exponential backoff between accept retries, reset once accepted.
+#[derive(Default)]
+pub struct Backoff(Option<Duration>);
+
+impl Backoff {
+    const MIN: Duration = Duration::from_millis(10);
+    const MAX: Duration = Duration::from_secs(1);
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.0.map_or(Self::MIN, |d| (d * 2).min(Self::MAX));
+        self.0 = Some(delay);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.0 = None;
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The socket file if any is removed on drop, abstract sockets have none.
+    Unix(UnixListener, Option<PathBuf>),
+    /// Fd numbers of connections accepted by a thread on a socket monoio can not adopt,
+    /// with a clone of the socket to query its options.
+    Inherited(UnixStream, std::net::TcpListener),
+}
+
+/// Accepted connection.
+pub enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Address of accepted connection.
+#[derive(Debug, Clone, Copy)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+impl Listener {
+    /// Bind a tcp address, or a unix socket path prefixed with `unix:`, or a linux abstract
+    /// socket name prefixed with `unix:@`.
+    /// Stale socket file left by a previous run is removed before binding.
+    /// IPV6_V6ONLY of IPv6 tcp sockets is set to opts.v6only if any, monoio can only bind
+    /// with the OS default so these sockets are accepted by a thread like inherited ones.
+    pub fn bind(addr: &str, opts: &Opts) -> std::io::Result<Self> {
+        let config = ListenerConfig::default().backlog(opts.backlog as i32);
+        let path = match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) if path.starts_with(ABSTRACT_PREFIX) => {
+                return Self::bind_abstract(&path[1..], &config)
+            }
+            Some(path) => Path::new(path),
+            None => {
+                let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::Other, "empty address")
+                })?;
+                return match opts.v6only {
+                    Some(v6only) if addr.is_ipv6() => {
+                        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+                        socket.set_only_v6(v6only)?;
+                        socket.set_reuse_port(true)?;
+                        socket.set_reuse_address(true)?;
+                        set_buffer_sizes(&socket, opts);
+                        socket.bind(&addr.into())?;
+                        socket.listen(opts.backlog as i32)?;
+                        Self::accept_thread(socket.into())
+                    }
+                    _ => {
+                        let listener = TcpListener::bind_with_config(addr, &config)?;
+                        // Accepted connections inherit the buffer sizes.
+                        set_buffer_sizes(&SockRef::from(&listener), opts);
+                        Ok(Self::Tcp(listener))
+                    }
+                };
+            }
+        };
+        if matches!(std::fs::symlink_metadata(path), Ok(meta) if meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        // SO_REUSEPORT is not supported by unix sockets.
+        let config = config.reuse_port(false);
+        let listener = UnixListener::bind_with_config(path, &config)?;
+        Ok(Self::Unix(listener, Some(path.to_path_buf())))
+    }
+
+    /// Bind a socket in the linux abstract namespace, named by a leading null byte, it
+    /// goes away with the last fd so nothing is left to clean up.
+    #[cfg(target_os = "linux")]
+    fn bind_abstract(name: &str, config: &ListenerConfig) -> std::io::Result<Self> {
+        let path = format!("\0{name}");
+        let config = config.clone().reuse_port(false);
+        let listener = UnixListener::bind_with_config(path, &config)?;
+        Ok(Self::Unix(listener, None))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn bind_abstract(_: &str, _: &ListenerConfig) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "abstract unix sockets are linux only",
+        ))
+    }
+
+    /// Hint for a bind of addr failed with EACCES for a privileged port.
+    pub fn privileged_port_hint(addr: &str, e: &std::io::Error) -> Option<String> {
+        if e.raw_os_error() != Some(libc::EACCES) || Self::is_unix(addr) {
+            return None;
+        }
+        let port: u16 = addr.rsplit_once(':')?.1.parse().ok()?;
+        let start = Self::unprivileged_port_start();
+        if port >= start {
+            return None;
+        }
+        let exe = std::env::current_exe()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|_| "shadow-tls".to_string());
+        Some(format!("ports below {start} need root or CAP_NET_BIND_SERVICE, grant it with `setcap cap_net_bind_service=+ep {exe}`, or start as root with --user to switch away once bound"))
+    }
+
+    /// Ports below this need CAP_NET_BIND_SERVICE, see ip(7).
+    fn unprivileged_port_start() -> u16 {
+        std::fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(1024)
+    }
+
+    /// Max listen backlog allowed by the kernel, larger backlogs are silently capped to it.
+    pub fn max_backlog() -> Option<u32> {
+        std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+    }
+
+    /// Accept on a listening tcp socket inherited from systemd.
+    /// Monoio can not adopt a listening fd, so a thread accepts on it with blocking
+    /// calls and hands connections over by their fd numbers through a socket pair.
+    pub fn inherited(fd: RawFd, opts: &Opts) -> std::io::Result<Self> {
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+        let listener = std::net::TcpListener::from(fd);
+        set_buffer_sizes(&SockRef::from(&listener), opts);
+        Self::accept_thread(listener)
+    }
+
+    fn accept_thread(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        listener.local_addr()?;
+        listener.set_nonblocking(false)?;
+        let socket = listener.try_clone()?;
+        let (mut tx, rx) = std::os::unix::net::UnixStream::pair()?;
+        std::thread::spawn(move || {
+            let mut backoff = Backoff::default();
+            loop {
+                match listener.accept() {
+                    Ok((conn, _)) => {
+                        backoff.reset();
+                        let fd = conn.into_raw_fd();
+                        // Fails once the worker dropped the listener.
+                        if tx.write_all(&fd.to_ne_bytes()).is_err() {
+                            unsafe { libc::close(fd) };
+                            break;
+                        }
+                    }
+                    // The worker reads EOF once tx is dropped and stops too.
+                    Err(e) => match AcceptError::classify(&e) {
+                        AcceptError::Connection => {}
+                        AcceptError::Resource => {
+                            let delay = backoff.next_delay();
+                            tracing::error!("Accept failed: {e}, retry in {delay:?}");
+                            std::thread::sleep(delay);
+                        }
+                        AcceptError::Fatal => {
+                            tracing::error!("Accept failed: {e}, stop listening");
+                            break;
+                        }
+                    },
+                }
+            }
+        });
+        Ok(Self::Inherited(UnixStream::from_std(rx)?, socket))
+    }
+
+    /// Local address and IPV6_V6ONLY in effect of an IPv6 tcp listener.
+    pub fn v6only(&self) -> Option<(SocketAddr, bool)> {
+        let socket = match self {
+            Self::Tcp(listener) => SockRef::from(listener),
+            Self::Inherited(_, listener) => SockRef::from(listener),
+            Self::Unix(..) => return None,
+        };
+        let addr = socket.local_addr().ok()?.as_socket()?;
+        match addr.is_ipv6() {
+            true => Some((addr, socket.only_v6().ok()?)),
+            false => None,
+        }
+    }
+
+    pub fn is_unix(addr: &str) -> bool {
+        addr.starts_with(UNIX_PREFIX)
+    }
+
+    pub async fn accept(&mut self) -> std::io::Result<(Conn, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Conn::Tcp(conn), PeerAddr::Tcp(addr)))
+            }
+            Self::Unix(listener, _) => {
+                let (conn, _) = listener.accept().await?;
+                Ok((Conn::Unix(conn), PeerAddr::Unix))
+            }
+            Self::Inherited(fds, _) => {
+                let (res, fd) = fds.read_exact(vec![0; std::mem::size_of::<RawFd>()]).await;
+                res?;
+                let fd = RawFd::from_ne_bytes(fd.try_into().unwrap());
+                let conn = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+                let addr = conn.peer_addr()?;
+                conn.set_nonblocking(true)?;
+                Ok((Conn::Tcp(TcpStream::from_std(conn)?), PeerAddr::Tcp(addr)))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Id of an accepted connection, unique in the process.
+pub fn next_connection_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Span of an accepted connection, all lines logged for it carry its id, so one
+/// connection can be grepped out of a busy log.
+pub fn connection_span(id: u64) -> tracing::Span {
+    tracing::info_span!("conn", id)
+}
+
+/// Listening fds passed by systemd socket activation, see sd_listen_fds(3).
+pub fn activated_fds() -> Option<Vec<RawFd>> {
+    const SD_LISTEN_FDS_START: RawFd = 3;
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let n: RawFd = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    Some((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_backoff() {
+        let err = std::io::Error::from_raw_os_error;
+        assert_eq!(
+            AcceptError::classify(&err(libc::EMFILE)),
+            AcceptError::Resource
+        );
+        assert_eq!(
+            AcceptError::classify(&err(libc::ECONNABORTED)),
+            AcceptError::Connection
+        );
+        assert_eq!(AcceptError::classify(&err(libc::EBADF)), AcceptError::Fatal);
+
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next_delay(), Backoff::MIN);
+        assert_eq!(backoff.next_delay(), Backoff::MIN * 2);
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), Backoff::MAX);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Backoff::MIN);
+    }
+
+    #[test]
+    fn test_privileged_port_hint() {
+        let denied = std::io::Error::from_raw_os_error(libc::EACCES);
+        let privileged = 443 < Listener::unprivileged_port_start();
+        let hint = |addr| Listener::privileged_port_hint(addr, &denied);
+        assert_eq!(hint("0.0.0.0:443").is_some(), privileged);
+        assert_eq!(hint("[::]:443").is_some(), privileged);
+        assert!(hint("0.0.0.0:8443").is_none());
+        assert!(hint("unix:/run/st:1").is_none());
+        let in_use = std::io::Error::from_raw_os_error(libc::EADDRINUSE);
+        assert!(Listener::privileged_port_hint("0.0.0.0:443", &in_use).is_none());
+    }
+
+    #[test]
+    fn test_abstract_unix() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let name = format!("shadow-tls-test-{}", std::process::id());
+            let mut listener = Listener::bind(&format!("unix:@{name}"), &Opts::default()).unwrap();
+            assert!(matches!(listener, Listener::Unix(_, None)));
+            let client = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+            client
+                .connect(&socket2::SockAddr::unix(format!("\0{name}")).unwrap())
+                .unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            assert!(matches!(conn, Conn::Unix(_)));
+            drop(listener);
+            let client = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+            assert!(client
+                .connect(&socket2::SockAddr::unix(format!("\0{name}")).unwrap())
+                .is_err());
+        });
+    }
+}
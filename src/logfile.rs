@@ -0,0 +1,239 @@
+//! Log file rotated by time or size, for hosts without journald.
+
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// When the log file is rotated, written as `daily`, `hourly` or `size:10MB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Daily,
+    Hourly,
+    /// Rotate before a write would grow the file beyond this many bytes.
+    Size(u64),
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => return Ok(Self::Daily),
+            "hourly" => return Ok(Self::Hourly),
+            _ => (),
+        }
+        let size = s
+            .strip_prefix("size:")
+            .ok_or_else(|| anyhow::anyhow!("expect daily, hourly or size:<bytes>, got {s}"))?;
+        let upper = size.to_ascii_uppercase();
+        let digits = upper.trim_end_matches(|c: char| !c.is_ascii_digit());
+        let unit = match &upper[digits.len()..] {
+            "" | "B" => 1,
+            "K" | "KB" => 1 << 10,
+            "M" | "MB" => 1 << 20,
+            "G" | "GB" => 1 << 30,
+            unit => anyhow::bail!("unknown size unit {unit:?}, expect B, KB, MB or GB"),
+        };
+        match digits.parse::<u64>() {
+            Ok(n) if n > 0 => match n.checked_mul(unit) {
+                Some(size) => Ok(Self::Size(size)),
+                None => anyhow::bail!("rotation size {size:?} is too large"),
+            },
+            _ => anyhow::bail!("invalid rotation size {size:?}"),
+        }
+    }
+}
+
+impl Display for Rotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daily => write!(f, "daily"),
+            Self::Hourly => write!(f, "hourly"),
+            Self::Size(size) => write!(f, "size:{size}B"),
+        }
+    }
+}
+
+/// Log file appended without buffering, so nothing is lost when the process exits.
+/// Rotated files are renamed with the UTC time of the period they cover appended, like
+/// `shadow-tls.log.2022-11-15` for daily rotation, or the rotation time for size rotation.
+pub struct RollingFile {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    file: File,
+    size: u64,
+    /// Time period of the current file for time rotation.
+    period: u64,
+}
+
+impl RollingFile {
+    /// Open path for appending. For time rotation a file left from an earlier period is
+    /// rotated on the first write.
+    pub fn open(path: &Path, rotation: Option<Rotation>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        let modified = meta.modified().map(unix_secs).unwrap_or_else(|_| now());
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file,
+            size: meta.len(),
+            period: Self::period_of(rotation, modified),
+        })
+    }
+
+    fn period_of(rotation: Option<Rotation>, secs: u64) -> u64 {
+        match rotation {
+            Some(Rotation::Daily) => secs / 86400,
+            Some(Rotation::Hourly) => secs / 3600,
+            _ => 0,
+        }
+    }
+
+    fn rotate_before_write(&mut self, len: usize) {
+        let now = now();
+        let suffix = match self.rotation {
+            None => return,
+            Some(Rotation::Size(max)) => {
+                if self.size == 0 || self.size + len as u64 <= max {
+                    return;
+                }
+                format_time(now, 6)
+            }
+            Some(Rotation::Daily) | Some(Rotation::Hourly) => {
+                let period = Self::period_of(self.rotation, now);
+                if period == self.period {
+                    return;
+                }
+                let suffix = match self.rotation {
+                    Some(Rotation::Daily) => format_time(self.period * 86400, 3),
+                    _ => format_time(self.period * 3600, 4),
+                };
+                self.period = period;
+                suffix
+            }
+        };
+        // Keep writing to the current file if it can not be rotated.
+        if let Err(e) = self.rotate(&suffix) {
+            eprintln!("rotate log file {} failed: {e}", self.path.display());
+        }
+    }
+
+    fn rotate(&mut self, suffix: &str) -> io::Result<()> {
+        let mut target = PathBuf::from(format!("{}.{suffix}", self.path.display()));
+        let mut n = 1;
+        while target.exists() {
+            target = PathBuf::from(format!("{}.{suffix}.{n}", self.path.display()));
+            n += 1;
+        }
+        std::fs::rename(&self.path, &target)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.rotate_before_write(buf.len());
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+/// Format the first fields of the UTC time like 2022-11-15-08-30-00.
+fn format_time(secs: u64, fields: usize) -> String {
+    utc_fields(secs)[..fields]
+        .iter()
+        .enumerate()
+        .map(|(i, v)| match i {
+            0 => format!("{v:04}"),
+            _ => format!("{v:02}"),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Year, month, day, hour, minute and second of the UTC time.
+pub(crate) fn utc_fields(secs: u64) -> [i64; 6] {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let secs = secs % 86400;
+    [
+        year,
+        month,
+        day,
+        (secs / 3600) as i64,
+        (secs / 60 % 60) as i64,
+        (secs % 60) as i64,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_file() {
+        assert_eq!("daily".parse::<Rotation>().unwrap(), Rotation::Daily);
+        assert_eq!(
+            "size:10MB".parse::<Rotation>().unwrap(),
+            Rotation::Size(10 << 20)
+        );
+        assert_eq!("size:512".parse::<Rotation>().unwrap(), Rotation::Size(512));
+        assert!("size:10TB".parse::<Rotation>().is_err());
+        assert!("size:0".parse::<Rotation>().is_err());
+        assert!("size:20000000000GB".parse::<Rotation>().is_err());
+        assert!("weekly".parse::<Rotation>().is_err());
+
+        assert_eq!(format_time(0, 6), "1970-01-01-00-00-00");
+        assert_eq!(format_time(1668501000, 3), "2022-11-15");
+        assert_eq!(format_time(951782400 + 3661, 6), "2000-02-29-01-01-01");
+
+        let dir = std::env::temp_dir().join(format!("shadow-tls-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        let mut file = RollingFile::open(&path, Some(Rotation::Size(10))).unwrap();
+        file.write_all(b"12345678").unwrap();
+        file.write_all(b"12").unwrap();
+        file.write_all(b"abc").unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+        assert_eq!(std::fs::read(dir.join(&names[1])).unwrap(), b"1234567812");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
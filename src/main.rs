@@ -1,26 +1,74 @@
-#![allow(stable_features)]
-#![feature(generic_associated_types)]
-#![feature(type_alias_impl_trait)]
-
-mod client;
-mod server;
+mod bench;
+mod check;
 mod sip003;
-mod stream;
-mod util;
 
-use std::{fmt::Display, rc::Rc, sync::Arc};
+use std::{
+    cell::Cell,
+    future::Future,
+    net::SocketAddr,
+    os::unix::{io::AsRawFd, net::UnixListener},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{atomic::Ordering, mpsc, Arc, Barrier, Mutex},
+    time::{Duration, Instant},
+};
 
+use anyhow::Context;
 use clap::{Parser, Subcommand};
-use monoio::net::TcpListener;
-use tracing::{error, info};
-use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
+use monoio::{io::AsyncReadRent, net::TcpListener};
+use tracing::{debug, error, info, warn, Instrument};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt, fmt::writer::BoxMakeWriter, prelude::*, EnvFilter,
+};
+
+use shadow_tls::{
+    access_log::AccessLog,
+    backend::Backend,
+    client::{CipherSuite, HandshakeOpts, ShadowTlsClient, Target, TlsVersion},
+    error::RelayError,
+    events::{self, InConnection},
+    filter::{Cidr, IpFilter},
+    limit::TotalLimits,
+    listener::{
+        self, connection_span, next_connection_id, AcceptError, Backoff, Conn, Listener, PeerAddr,
+    },
+    logfile::RollingFile,
+    metrics::{self, Liveness, Metrics, Relayed, SniMetrics},
+    notify::{Heartbeats, Notifier},
+    proxy,
+    server::{DataServer, ShadowTlsServer},
+    signal::ShutdownSignal,
+    sni::SniRoute,
+    stream::PrefixedReadStream,
+    upstream::{Health, LbPolicy, Upstreams},
+    util::{
+        gen_password, mod_tcp_conn, open_files_limit, pin_thread_to_cpu, reset_on_close,
+        set_fast_open_listener, validate_address, Credentials, Direction, FDS_PER_CONNECTION,
+    },
+    verify::{CertChangeAction, Pin},
+    IoDriver, LogFormat, Opts, Protocol, RejectWith,
+};
+
+use crate::{bench::BenchArgs, check::CheckArgs};
 
-use crate::{client::ShadowTlsClient, server::ShadowTlsServer, util::mod_tcp_conn};
+/// Printed by `--version`, `-V` prints the crate version only.
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("SHADOW_TLS_COMMIT"),
+    "\nbuilt: ",
+    env!("SHADOW_TLS_BUILD_TIME"),
+    "\nrustc: ",
+    env!("SHADOW_TLS_RUSTC"),
+    "\nfeatures: ",
+    env!("SHADOW_TLS_FEATURES"),
+);
 
 #[derive(Parser, Debug)]
 #[clap(
     author,
     version,
+    long_version = LONG_VERSION,
     about,
     long_about = "A proxy to expose real tls handshake to the firewall.\nGithub: github.com/ihciah/shadow-tls"
 )]
@@ -31,204 +79,1257 @@ struct Args {
     opts: Opts,
 }
 
-#[derive(Parser, Debug, Default, Clone)]
-pub struct Opts {
-    #[clap(short, long, help = "Set parallelism manually")]
-    threads: Option<u8>,
-    #[clap(short, long, help = "Set TCP_NODELAY")]
-    nodelay: bool,
-}
-
-impl Display for Opts {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.threads {
-            Some(t) => {
-                write!(f, "fixed {t} threads")
-            }
-            None => {
-                write!(f, "auto adjusted threads")
-            }
-        }?;
-        write!(f, "; nodelay: {}", self.nodelay)
-    }
-}
-
 #[derive(Subcommand, Debug)]
 enum Commands {
     #[clap(about = "Run client side")]
-    Client {
-        #[clap(
-            long = "listen",
-            default_value = "[::1]:8080",
-            help = "Shadow-tls client listen address"
-        )]
-        listen: String,
-        #[clap(
-            long = "server",
-            help = "Your shadow-tls server address(like 1.2.3.4:443)"
-        )]
-        server_addr: String,
-        #[clap(long = "sni", help = "TLS handshake SNI(like cloud.tencent.com)")]
-        tls_name: String,
-        #[clap(long = "password", help = "Password")]
-        password: String,
-    },
+    Client(ClientArgs),
     #[clap(about = "Run server side")]
-    Server {
-        #[clap(
-            long = "listen",
-            default_value = "[::1]:443",
-            help = "Shadow-tls server listen address"
-        )]
-        listen: String,
-        #[clap(
-            long = "server",
-            help = "Your data server address(like 127.0.0.1:8080)"
-        )]
-        server_addr: String,
-        #[clap(
-            long = "tls",
-            help = "TLS handshake server address(with port, like cloud.tencent.com:443)"
-        )]
-        tls_addr: String,
-        #[clap(long = "password", help = "Password")]
-        password: String,
+    Server(ServerArgs),
+    #[clap(about = "Generate a random password for both sides")]
+    GenPassword {
+        #[clap(long = "length", default_value_t = 16, help = "Password length")]
+        length: usize,
     },
+    #[clap(about = "Check client config against the handshake server and shadow-tls server")]
+    Check(CheckArgs),
+    #[clap(about = "Measure throughput and handshakes through an in-process server and client")]
+    Bench(BenchArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ClientArgs {
+    #[clap(
+        long = "listen",
+        value_delimiter = ',',
+        default_value = "[::1]:8080",
+        help = "Shadow-tls client listen addresses(comma separated, unix:/path for unix socket, unix:@name for linux abstract socket)"
+    )]
+    listen: Vec<String>,
+    #[clap(
+        long = "server",
+        value_delimiter = ',',
+        required = true,
+        help = "Your shadow-tls server address(like 1.2.3.4:443), comma separated addresses are balanced by --lb-policy"
+    )]
+    server_addrs: Vec<String>,
+    #[clap(
+        long = "lb-policy",
+        value_enum,
+        default_value_t = LbPolicy::RoundRobin,
+        help = "How to pick one of the servers per connection, failing servers are skipped for a while"
+    )]
+    lb_policy: LbPolicy,
+    #[clap(
+        long = "health-interval",
+        default_value_t = 0,
+        help = "Seconds between handshakes through each server to check its health, unhealthy servers are skipped until they pass. 0 to disable"
+    )]
+    health_interval: u64,
+    #[clap(
+        long = "health-timeout",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds for a health check handshake to finish"
+    )]
+    health_timeout: u64,
+    #[clap(
+        long = "sni",
+        value_delimiter = ',',
+        required = true,
+        help = "TLS handshake SNI(like cloud.tencent.com), comma separated names are picked randomly per connection"
+    )]
+    tls_names: Vec<String>,
+    #[clap(
+        long = "password",
+        help = "Password, read from --password-file or env SHADOW_TLS_PASSWORD if not set"
+    )]
+    password: Option<String>,
+    #[clap(long = "password-file", help = "File containing the password")]
+    password_file: Option<PathBuf>,
+    #[clap(
+        long = "alpn",
+        value_delimiter = ',',
+        help = "ALPN protocols to advertise in handshake(comma separated, like h2,http/1.1)"
+    )]
+    alpn: Vec<String>,
+    #[clap(
+        long = "pin-sha256",
+        value_delimiter = ',',
+        help = "Base64 sha256 hashes of the tls server's public key, handshake is aborted if the certificate matches none(comma separated)"
+    )]
+    pin_sha256: Vec<Pin>,
+    #[clap(
+        long = "insecure",
+        help = "DANGEROUS: accept any certificate of the tls server, for self-signed handshake servers only"
+    )]
+    insecure: bool,
+    #[clap(
+        long = "verify-name",
+        conflicts_with = "insecure",
+        help = "Verify the certificate of the tls server for this name instead of the sni sent, which is the default"
+    )]
+    verify_name: Option<String>,
+    #[clap(
+        long = "cert-change-action",
+        value_enum,
+        default_value = "log",
+        help = "What to do when the tls server presents another public key than in the first handshake of the sni(log: warn and accept the new one, reject: fail the handshakes until restart)"
+    )]
+    cert_change_action: CertChangeAction,
+    #[clap(
+        long = "tls-min-version",
+        default_value = "1.2",
+        help = "Lowest tls version offered in handshake(1.2 or 1.3), match what the handshake server supports"
+    )]
+    tls_min_version: TlsVersion,
+    #[clap(
+        long = "tls-max-version",
+        default_value = "1.3",
+        help = "Highest tls version offered in handshake(1.2 or 1.3)"
+    )]
+    tls_max_version: TlsVersion,
+    #[clap(
+        long = "cipher-suites",
+        value_delimiter = ',',
+        help = "Cipher suites offered in handshake in this order(comma separated, like TLS13_AES_128_GCM_SHA256,TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256), limited to the suites of rustls"
+    )]
+    cipher_suites: Vec<CipherSuite>,
+    #[clap(
+        long = "accept-proxy-protocol",
+        help = "Read the client address from a PROXY protocol v1 or v2 header sent by the proxy in front"
+    )]
+    accept_proxy_protocol: bool,
+    #[clap(
+        long = "socks5",
+        help = "Serve socks5 on listen address and relay to the requested destinations, server must run with --socks5 too"
+    )]
+    socks5: bool,
+    #[clap(
+        long = "backend-tag",
+        conflicts_with = "socks5",
+        help = "Routing tag sent to the server to pick a backend of its --backend-map"
+    )]
+    backend_tag: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ServerArgs {
+    #[clap(
+        long = "listen",
+        value_delimiter = ',',
+        default_value = "[::1]:443",
+        help = "Shadow-tls server listen addresses(comma separated, unix:/path for unix socket, unix:@name for linux abstract socket)"
+    )]
+    listen: Vec<String>,
+    #[clap(
+        long = "server",
+        required_unless_present = "socks5",
+        help = "Your data server address(like 127.0.0.1:8080)"
+    )]
+    server_addr: Option<String>,
+    #[clap(
+        long = "tls",
+        help = "TLS handshake server address(with port, like cloud.tencent.com:443)"
+    )]
+    tls_addr: String,
+    #[clap(
+        long = "password",
+        help = "Password, repeat it to accept any of several passwords. Read from --password-file or env SHADOW_TLS_PASSWORD if not set"
+    )]
+    passwords: Vec<String>,
+    #[clap(long = "password-file", help = "File containing the password")]
+    password_file: Option<PathBuf>,
+    #[clap(
+        long = "sni-map",
+        value_delimiter = ',',
+        help = "Handshake servers by SNI like *.example.com=example.com:443, separated by comma. --tls is used if none matches"
+    )]
+    sni_map: Vec<SniRoute>,
+    #[clap(
+        long = "backend-map",
+        value_delimiter = ',',
+        conflicts_with = "socks5",
+        help = "Data servers by the routing tag of clients like web=127.0.0.1:8080, separated by comma. --server is used for clients without a tag"
+    )]
+    backend_map: Vec<Backend>,
+    #[clap(
+        long = "allow",
+        value_delimiter = ',',
+        help = "Only accept connections from these networks(comma separated, like 10.0.0.0/8,2001:db8::/32)"
+    )]
+    allow: Vec<Cidr>,
+    #[clap(
+        long = "deny",
+        value_delimiter = ',',
+        help = "Close connections from these networks(comma separated), takes precedence over --allow"
+    )]
+    deny: Vec<Cidr>,
+    #[clap(
+        long = "send-proxy-protocol",
+        conflicts_with = "socks5",
+        help = "Send a PROXY protocol v2 header with the client address to data server"
+    )]
+    send_proxy_protocol: bool,
+    #[clap(
+        long = "accept-proxy-protocol",
+        help = "Read the client address from a PROXY protocol v1 or v2 header sent by the load balancer in front, the tls stream follows it. --allow and --deny still apply to the load balancer address"
+    )]
+    accept_proxy_protocol: bool,
+    #[clap(
+        long = "socks5",
+        conflicts_with = "server_addr",
+        help = "Connect to the destinations sent by socks5 clients instead of a fixed data server"
+    )]
+    socks5: bool,
+}
+
+/// State shared by all worker threads.
+#[derive(Clone)]
+struct Shared {
+    shutdown: ShutdownSignal,
+    metrics: Arc<Metrics>,
+    total_limits: Option<Arc<TotalLimits>>,
+    /// Health of the client upstreams.
+    health: Option<Arc<Health>>,
+    /// Each worker sends once its listeners are bound.
+    ready: mpsc::Sender<()>,
+    /// Workers wait here after ready until the main thread switched credentials.
+    privileges_dropped: Option<Arc<Barrier>>,
+    /// Beaten by workers if systemd watches the service.
+    heartbeats: Option<Arc<Heartbeats>>,
+    access_log: Option<Arc<AccessLog>>,
 }
 
 impl Args {
-    async fn start(&self) {
+    /// Fill in the password from --password-file or SHADOW_TLS_PASSWORD if --password
+    /// is not set.
+    fn resolve_passwords(&mut self) -> anyhow::Result<()> {
+        match &mut self.cmd {
+            Commands::Client(args) | Commands::Check(CheckArgs { client: args, .. }) => {
+                if args.password.is_none() {
+                    args.password = Some(fallback_password(args.password_file.as_deref())?);
+                }
+            }
+            Commands::Server(args) => {
+                if args.passwords.is_empty() {
+                    args.passwords = vec![fallback_password(args.password_file.as_deref())?];
+                }
+            }
+            Commands::GenPassword { .. } | Commands::Bench(_) => (),
+        }
+        Ok(())
+    }
+
+    /// Check the form of addresses before binding or connecting anything, so a typo is
+    /// reported once instead of by every worker or connection.
+    fn validate_addresses(&self) -> anyhow::Result<()> {
+        let listen = |listen: &[String]| {
+            listen
+                .iter()
+                .filter(|addr| !Listener::is_unix(addr))
+                .try_for_each(|addr| validate_address("listen", addr))
+        };
         match &self.cmd {
-            Commands::Client {
-                listen,
-                server_addr,
-                tls_name,
-                password,
-            } => {
-                run_client(
-                    listen.clone(),
-                    server_addr.clone(),
-                    tls_name.clone(),
-                    password.clone(),
-                    self.opts.clone(),
-                )
-                .await
-                .expect("client exited");
-            }
-            Commands::Server {
-                listen,
-                server_addr,
-                tls_addr,
-                password,
-            } => {
-                run_server(
-                    listen.clone(),
-                    server_addr.clone(),
-                    tls_addr.clone(),
-                    password.clone(),
-                    self.opts.clone(),
-                )
-                .await
-                .expect("server exited");
+            Commands::Client(args) => {
+                listen(&args.listen)?;
+                for addr in &args.server_addrs {
+                    validate_address("server", addr)?;
+                }
+            }
+            Commands::Check(args) => {
+                for addr in &args.client.server_addrs {
+                    validate_address("server", addr)?;
+                }
+            }
+            Commands::Server(args) => {
+                listen(&args.listen)?;
+                if let Some(addr) = &args.server_addr {
+                    validate_address("server", addr)?;
+                }
+                validate_address("tls", &args.tls_addr)?;
+            }
+            Commands::GenPassword { .. } | Commands::Bench(_) => (),
+        }
+        if let Some(addr) = &self.opts.metrics_listen {
+            validate_address("metrics listen", addr)?;
+        }
+        if let Some(addr) = &self.opts.admin_listen {
+            validate_address("admin listen", addr)?;
+        }
+        Ok(())
+    }
+
+    /// Refuse --plain-relay where it can not work, or on a listen address reachable from
+    /// other hosts unless --plain-relay-public confirms it.
+    fn check_plain_relay(&self) -> anyhow::Result<()> {
+        if !self.opts.plain_relay {
+            return Ok(());
+        }
+        let listen = match &self.cmd {
+            Commands::Client(args) if args.socks5 => {
+                anyhow::bail!("plain relay does not work with socks5")
+            }
+            Commands::Server(args) if args.server_addr.is_none() => {
+                anyhow::bail!("plain relay does not work with socks5")
+            }
+            Commands::Client(ClientArgs { listen, .. })
+            | Commands::Server(ServerArgs { listen, .. }) => listen,
+            Commands::Check(_) | Commands::GenPassword { .. } | Commands::Bench(_) => return Ok(()),
+        };
+        let public = listen.iter().find(|addr| {
+            !Listener::is_unix(addr)
+                && !matches!(addr.parse::<SocketAddr>(), Ok(addr) if addr.ip().is_loopback())
+        });
+        match (public, self.opts.plain_relay_public) {
+            (Some(addr), false) => anyhow::bail!(
+                "plain relay forwards without the tls camouflage, refusing to listen on {addr} \
+                 which is not loopback, add --plain-relay-public if it is meant"
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    async fn start(&self, shared: Shared, worker: usize) -> anyhow::Result<()> {
+        match &self.cmd {
+            Commands::Client(args) => {
+                run_client(args.clone(), self.opts.clone(), shared, worker).await
+            }
+            Commands::Server(args) => {
+                run_server(args.clone(), self.opts.clone(), shared, worker).await
+            }
+            Commands::Check(_) | Commands::GenPassword { .. } | Commands::Bench(_) => {
+                unreachable!("only client and server run in workers")
             }
         }
     }
 }
 
 fn main() {
+    let mut args = match sip003::get_sip003_arg() {
+        Ok(Some(a)) => a,
+        Ok(None) => Args::parse(),
+        Err(e) => {
+            // Logging is not initialized yet since it depends on the args.
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = args
+        .resolve_passwords()
+        .and_then(|_| args.validate_addresses())
+        .and_then(|_| args.check_plain_relay())
+    {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+    let args = Arc::new(args);
+    if let Commands::GenPassword { length } = args.cmd {
+        match gen_password(length) {
+            Ok(password) => println!("{password}"),
+            Err(e) => {
+                eprintln!("unable to generate password: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // Stdout is left to the access log if it is written there.
+    let writer = match args.opts.access_log.as_deref().and_then(Path::to_str) {
+        Some("-") => BoxMakeWriter::new(std::io::stderr),
+        _ => BoxMakeWriter::new(std::io::stdout),
+    };
+    let (text_layer, json_layer) = match args.opts.log_format {
+        LogFormat::Text => (Some(fmt::layer().with_writer(writer)), None),
+        LogFormat::Json => (None, Some(fmt::layer().json().with_writer(writer))),
+    };
+    let log_file = match &args.opts.log_file {
+        Some(path) => match RollingFile::open(path, args.opts.log_rotate) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("unable to open log file {}: {e}", path.display());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let (text_file_layer, json_file_layer) = match (log_file, args.opts.log_format) {
+        (None, _) => (None, None),
+        (Some(file), LogFormat::Text) => {
+            (Some(fmt::layer().with_ansi(false).with_writer(file)), None)
+        }
+        (Some(file), LogFormat::Json) => (None, Some(fmt::layer().json().with_writer(file))),
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
+        .with(text_layer)
+        .with(json_layer)
+        .with(text_file_layer)
+        .with(json_file_layer)
+        .with(log_filter(&args.opts.log_level))
         .init();
-    let args = match sip003::get_sip003_arg() {
-        Some(a) => Arc::new(a),
-        None => Arc::new(Args::parse()),
+    if let Commands::Check(check) = &args.cmd {
+        if !check::run(check.clone(), args.opts.clone()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Commands::Bench(bench) = &args.cmd {
+        if !bench::run(bench.clone(), args.opts.clone()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let credentials =
+        match Credentials::lookup(args.opts.user.as_deref(), args.opts.group.as_deref()) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                error!("{e:#}");
+                std::process::exit(1);
+            }
+        };
+    let parallelism = get_parallelism(&args);
+    let privileges_dropped = credentials.map(|_| Arc::new(Barrier::new(parallelism + 1)));
+    let (ready, workers_ready) = mpsc::channel();
+    let notifier = Notifier::from_env();
+    // Opened before privileges are dropped, like the log file.
+    let access_log = args
+        .opts
+        .access_log
+        .as_deref()
+        .map(|path| match AccessLog::open(path) {
+            Ok(access_log) => Arc::new(access_log),
+            Err(e) => {
+                error!("Open access log {} failed: {e}", path.display());
+                std::process::exit(1);
+            }
+        });
+    let shared = Shared {
+        shutdown: ShutdownSignal::install().expect("unable to install signal handler"),
+        metrics: Arc::new(Metrics {
+            sni: SniMetrics::new(args.opts.sni_metrics),
+            liveness: Liveness::new(parallelism, Duration::from_secs(args.opts.liveness_timeout)),
+            ..Default::default()
+        }),
+        total_limits: args
+            .opts
+            .total_rate_limit
+            .map(|rate| Arc::new(TotalLimits::new(rate))),
+        health: match &args.cmd {
+            Commands::Client(client) => Some(Arc::new(Health::new(client.server_addrs.clone()))),
+            _ => None,
+        },
+        ready,
+        privileges_dropped: privileges_dropped.clone(),
+        heartbeats: notifier
+            .as_ref()
+            .and_then(Notifier::watchdog)
+            .map(|watchdog| Arc::new(Heartbeats::new(parallelism, watchdog))),
+        access_log,
     };
+    // Bound before privileges are dropped, like listeners.
+    let event_socket = args.opts.event_socket.as_deref();
+    if let Some(path) = event_socket {
+        // A socket left by an unclean exit fails binding.
+        let _ = std::fs::remove_file(path);
+        match UnixListener::bind(path) {
+            Ok(listener) => {
+                let metrics = shared.metrics.clone();
+                std::thread::spawn(move || events::serve(listener, metrics));
+            }
+            Err(e) => {
+                error!("Bind event socket {} failed: {e}", path.display());
+                std::process::exit(1);
+            }
+        }
+    }
+    let shutdown = shared.shutdown.clone();
+    let heartbeats = shared.heartbeats.clone();
+    let metrics = shared.metrics.clone();
     let mut threads = Vec::new();
-    let parallelism = get_parallelism(&args);
     info!("Started with parallelism {parallelism}");
-    for _ in 0..parallelism {
+    if args.opts.plain_relay {
+        warn!(
+            "Plain relay is on: connections are forwarded without shadow-tls, for debugging only"
+        );
+    }
+    if let Some(max) = args.opts.max_connections {
+        let needed = max.saturating_mul(FDS_PER_CONNECTION);
+        match open_files_limit() {
+            Some(limit) if limit < needed => warn!(
+                "Open files limit {limit} is below the {needed} fds of --max-connections {max}, raise it with ulimit -n"
+            ),
+            _ => (),
+        }
+    }
+    let uring = monoio::utils::detect_uring();
+    match (args.opts.io_driver, uring, args.opts.iouring_entries) {
+        (IoDriver::Auto | IoDriver::Iouring, true, _) => info!("Using io_uring"),
+        (IoDriver::Iouring, false, _) => {
+            error!("io_uring is not available: it needs linux 5.6 or later, and MONOIO_FORCE_LEGACY_DRIVER unset");
+            std::process::exit(1);
+        }
+        (IoDriver::Auto, false, None) => info!("io_uring is not available, using epoll instead"),
+        (IoDriver::Auto, false, Some(_)) => {
+            warn!("io_uring is not available, using epoll instead and ignoring --iouring-entries")
+        }
+        (IoDriver::Epoll, _, None) => info!("Using epoll"),
+        (IoDriver::Epoll, _, Some(_)) => warn!("Using epoll, --iouring-entries is ignored"),
+    }
+    for worker in 0..parallelism {
         let args_clone = args.clone();
+        let shared = shared.clone();
         let t = std::thread::spawn(move || {
-            let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-                .enable_timer()
-                .build()
+            if let Some(cpus) = &args_clone.opts.cpu_affinity {
+                let cpu = match cpus.is_empty() {
+                    true => worker,
+                    false => cpus[worker % cpus.len()],
+                };
+                match pin_thread_to_cpu(cpu) {
+                    Ok(()) => info!("Pinned worker {worker} to cpu {cpu}"),
+                    Err(e) => warn!("Pin worker {worker} to cpu {cpu} failed: {e}"),
+                }
+            }
+            let opts = args_clone.opts.clone();
+            let started = block_on(&opts, args_clone.start(shared, worker))
                 .expect("unable to build monoio runtime");
-            rt.block_on(args_clone.start());
+            // Workers fail for the same bad config, so the first failure exits the process.
+            if let Err(e) = started {
+                error!("{e:#}");
+                std::process::exit(1);
+            }
         });
         threads.push(t);
     }
+    // Receiving fails if all workers are gone before binding.
+    drop(shared);
+    let pid_file = args.opts.pid_file.as_deref();
+    let mut notify_thread = None;
+    if (pid_file.is_some() || credentials.is_some() || notifier.is_some())
+        && (0..parallelism).all(|_| workers_ready.recv().is_ok())
+    {
+        if let Some(path) = pid_file {
+            if let Err(e) = std::fs::write(path, format!("{}\n", std::process::id())) {
+                error!("Write pid file {} failed: {e}", path.display());
+                std::process::exit(1);
+            }
+        }
+        if let (Some(credentials), Some(barrier)) = (credentials, privileges_dropped) {
+            let Credentials { uid, gid } = credentials;
+            if let Err(e) = credentials.apply() {
+                error!("Switch to uid {uid} gid {gid} failed: {e}");
+                std::process::exit(1);
+            }
+            info!("Switched to uid {uid} gid {gid}");
+            barrier.wait();
+        }
+        if let Some(notifier) = notifier {
+            notifier.notify("READY=1");
+            let shutdown = shutdown.clone();
+            notify_thread = Some(std::thread::spawn(move || {
+                notifier.run(&shutdown, heartbeats.as_deref(), &metrics.liveness)
+            }));
+        }
+    }
     threads.into_iter().for_each(|t| {
         let _ = t.join();
     });
+    // Workers may drain before the notifier polls, wait for it to send STOPPING=1.
+    if let (true, Some(t)) = (shutdown.is_triggered(), notify_thread) {
+        let _ = t.join();
+    }
+    if let Some(path) = pid_file {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(path) = event_socket {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Run fut to the end on a new runtime of the driver chosen by --io-driver, with the
+/// io_uring size of opts.
+/// The auto driver is io_uring, or epoll if io_uring is not available.
+fn block_on<F: Future>(opts: &Opts, fut: F) -> std::io::Result<F::Output> {
+    use monoio::RuntimeBuilder;
+
+    fn with_entries<D>(builder: RuntimeBuilder<D>, opts: &Opts) -> RuntimeBuilder<D> {
+        match opts.iouring_entries {
+            Some(entries) => builder.with_entries(entries),
+            None => builder,
+        }
+    }
+    match opts.io_driver {
+        IoDriver::Auto => {
+            let builder = with_entries(RuntimeBuilder::<monoio::FusionDriver>::new(), opts);
+            Ok(builder.enable_timer().build()?.block_on(fut))
+        }
+        #[cfg(target_os = "linux")]
+        IoDriver::Iouring => {
+            let builder = with_entries(RuntimeBuilder::<monoio::IoUringDriver>::new(), opts);
+            Ok(builder.enable_timer().build()?.block_on(fut))
+        }
+        #[cfg(not(target_os = "linux"))]
+        IoDriver::Iouring => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "io_uring is only available on linux",
+        )),
+        IoDriver::Epoll => {
+            let builder = RuntimeBuilder::<monoio::LegacyDriver>::new();
+            Ok(builder.enable_timer().build()?.block_on(fut))
+        }
+    }
+}
+
+/// Filter by RUST_LOG if set, or by --log-level.
+/// Targets not named by --log-level are logged at info level.
+fn log_filter(log_level: &str) -> EnvFilter {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+    match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) if !directives.is_empty() => builder.parse_lossy(directives),
+        // A later directive of the same target replaces the earlier one.
+        _ => builder.parse_lossy(format!("info,{log_level}")),
+    }
 }
 
 fn get_parallelism(args: &Args) -> usize {
-    if let Some(n) = args.opts.threads {
-        return n as usize;
+    let cores = || {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    };
+    match (args.opts.threads, args.opts.cpu_affinity.as_deref()) {
+        (Some(threads), _) => threads.count(cores()),
+        // A worker for each listed cpu.
+        (None, Some(cpus)) if !cpus.is_empty() => cpus.len(),
+        (None, _) => cores(),
+    }
+}
+
+const PASSWORD_ENV: &str = "SHADOW_TLS_PASSWORD";
+
+/// Password used if --password is not set, read from --password-file if set, otherwise
+/// from SHADOW_TLS_PASSWORD. Trailing newlines of the file are trimmed.
+fn fallback_password(file: Option<&Path>) -> anyhow::Result<String> {
+    let password = match file {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read password file {}", path.display()))?
+            .trim_end_matches(|c| c == '\r' || c == '\n')
+            .to_owned(),
+        None => std::env::var(PASSWORD_ENV).map_err(|_| {
+            anyhow::anyhow!(
+                "password is required, set --password, --password-file or {PASSWORD_ENV}"
+            )
+        })?,
+    };
+    if password.is_empty() {
+        anyhow::bail!("password is empty");
+    }
+    Ok(password)
+}
+
+impl ClientArgs {
+    /// Build the client, listen addresses are not touched.
+    fn build(
+        self,
+        opts: Opts,
+        metrics: Arc<Metrics>,
+        total_limits: Option<Arc<TotalLimits>>,
+        health: Arc<Health>,
+    ) -> anyhow::Result<ShadowTlsClient<String>> {
+        if !self.pin_sha256.is_empty() {
+            info!(
+                "Certificate pins: {}",
+                self.pin_sha256
+                    .iter()
+                    .map(Pin::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if let Some(name) = &self.verify_name {
+            info!("Certificates are verified for {name} instead of the sni");
+        }
+        if self.insecure {
+            warn!(
+                "!!! --insecure is set, the certificate of the tls server will NOT be verified !!!"
+            );
+            warn!("Anyone in the middle can impersonate the handshake server, only use it for self-signed servers under your control");
+        }
+        let password = self
+            .password
+            .context("password is resolved before building the client")?;
+        let handshake = HandshakeOpts {
+            server_names: self.tls_names,
+            alpn: self.alpn,
+            pins: self.pin_sha256,
+            insecure: self.insecure,
+            verify_name: self.verify_name,
+            min_version: self.tls_min_version,
+            max_version: self.tls_max_version,
+            cipher_suites: self.cipher_suites,
+            cert_change_action: self.cert_change_action,
+        };
+        ShadowTlsClient::new(
+            handshake,
+            Upstreams::new(self.server_addrs, self.lb_policy, health)?,
+            password,
+            match (self.socks5, self.backend_tag) {
+                (true, _) => Target::Socks5,
+                (false, Some(tag)) => Target::Tagged(tag),
+                (false, None) => Target::Default,
+            },
+            opts,
+            metrics,
+            total_limits,
+        )
     }
-    std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(1)
 }
 
 async fn run_client(
-    listen: String,
-    server_addr: String,
-    tls_name: String,
-    password: String,
+    args: ClientArgs,
     opts: Opts,
+    shared: Shared,
+    worker: usize,
 ) -> anyhow::Result<()> {
-    info!("Client is running!\nListen address: {listen}\nRemote address: {server_addr}\nTLS server name: {tls_name}\nOpts: {opts}");
-    let nodelay = opts.nodelay;
-    let shadow_client = Rc::new(ShadowTlsClient::new(
-        &tls_name,
-        server_addr,
-        password,
-        opts,
+    let ClientArgs {
+        listen,
+        server_addrs,
+        lb_policy,
+        tls_names,
+        alpn,
+        socks5,
+        accept_proxy_protocol,
+        health_interval,
+        health_timeout,
+        ..
+    } = &args;
+    info!("Client is running!\nListen address: {}\nRemote address: {}\nLB policy: {lb_policy:?}\nTLS server names: {tls_names:?}\nALPN: {alpn:?}\nSocks5: {socks5}\nOpts: {opts}", listen.join(", "), server_addrs.join(", "));
+    let listen = listen.clone();
+    let accept_proxy_protocol = *accept_proxy_protocol;
+    let header_timeout = Duration::from_secs(opts.handshake_timeout);
+    let (health_interval, health_timeout) = (*health_interval, *health_timeout);
+    let shadow_client = Rc::new(args.build(
+        opts.clone(),
+        shared.metrics.clone(),
+        shared.total_limits.clone(),
+        shared.health.clone().expect("health is created for client"),
     )?);
-    let listener = TcpListener::bind(&listen)?;
-    loop {
-        match listener.accept().await {
-            Ok((mut conn, addr)) => {
-                info!("Accepted a connection from {addr}");
-                let client = shadow_client.clone();
-                mod_tcp_conn(&mut conn, true, nodelay);
-                monoio::spawn(async move { client.relay(conn, addr).await });
-            }
-            Err(e) => {
-                error!("Accept failed: {e}");
+    // Health is shared by all workers, so the first one checks for all of them.
+    if worker == 0 && health_interval != 0 {
+        let client = shadow_client.clone();
+        monoio::spawn(async move {
+            client
+                .check_health(
+                    Duration::from_secs(health_interval),
+                    Duration::from_secs(health_timeout),
+                )
+                .await
+        });
+    }
+    let listeners = bind_listeners(&listen, worker, &opts)?;
+    if opts.pool_size != 0 {
+        let client = shadow_client.clone();
+        monoio::spawn(async move { client.fill_pool().await });
+    }
+    serve(
+        listeners,
+        worker,
+        &opts,
+        shared,
+        IpFilter::default(),
+        move |conn, addr| {
+            let client = shadow_client.clone();
+            async move {
+                match (conn, accept_proxy_protocol) {
+                    (Conn::Tcp(conn), false) => client.relay(conn, addr).await,
+                    (Conn::Unix(conn), false) => client.relay(conn, addr).await,
+                    (Conn::Tcp(conn), true) => {
+                        let (conn, addr) = accept_proxy_header(conn, addr, header_timeout).await?;
+                        client.relay(conn, addr).await
+                    }
+                    (Conn::Unix(conn), true) => {
+                        let (conn, addr) = accept_proxy_header(conn, addr, header_timeout).await?;
+                        client.relay(conn, addr).await
+                    }
+                }
             }
+        },
+    )
+    .await
+}
+
+/// Read the PROXY protocol header, connections with an invalid one are closed.
+async fn accept_proxy_header<S: AsyncReadRent + AsRawFd>(
+    conn: S,
+    addr: PeerAddr,
+    timeout: Duration,
+) -> anyhow::Result<(PrefixedReadStream<S>, PeerAddr)> {
+    match proxy::accept(conn, addr, timeout).await {
+        Ok((conn, client_addr)) => {
+            debug!(peer = %addr, client = %client_addr, "PROXY protocol header accepted");
+            Ok((conn, client_addr))
+        }
+        Err(e) => {
+            warn!(peer = %addr, error = %e, "Invalid PROXY protocol header");
+            Err(e.into())
         }
     }
 }
 
 async fn run_server(
-    listen: String,
-    server_addr: String,
-    tls_addr: String,
-    password: String,
+    args: ServerArgs,
     opts: Opts,
+    shared: Shared,
+    worker: usize,
 ) -> anyhow::Result<()> {
-    info!("Server is running!\nListen address: {listen}\nRemote address: {server_addr}\nTLS server address: {tls_addr}\nOpts: {opts}");
-    let nodelay = opts.nodelay;
-    let shadow_server = Rc::new(ShadowTlsServer::new(tls_addr, server_addr, password, opts));
-    let listener = TcpListener::bind(&listen)?;
-    loop {
-        match listener.accept().await {
-            Ok((mut conn, addr)) => {
-                info!("Accepted a connection from {addr}");
-                mod_tcp_conn(&mut conn, true, nodelay);
-                let server = shadow_server.clone();
-                monoio::spawn(async move { server.relay(conn).await });
+    let ServerArgs {
+        listen,
+        server_addr,
+        tls_addr,
+        passwords,
+        sni_map,
+        allow,
+        deny,
+        send_proxy_protocol,
+        accept_proxy_protocol,
+        backend_map,
+        ..
+    } = args;
+    match opts.protocol_version {
+        Protocol::Auto => anyhow::bail!("protocol auto is for clients only"),
+        Protocol::V1 if server_addr.is_none() || !backend_map.is_empty() => {
+            anyhow::bail!("socks5 and backend map need protocol v2")
+        }
+        Protocol::V1 | Protocol::V2 => (),
+    }
+    let remote = server_addr.as_deref().unwrap_or("socks5 targets");
+    let routes: Vec<_> = sni_map.iter().map(ToString::to_string).collect();
+    let backends: Vec<_> = backend_map.iter().map(ToString::to_string).collect();
+    info!("Server is running!\nListen address: {}\nRemote address: {remote}\nTLS server address: {tls_addr}\nSNI map: {routes:?}\nBackend map: {backends:?}\nOpts: {opts}", listen.join(", "));
+    if !allow.is_empty() || !deny.is_empty() {
+        let join = |cidrs: &[Cidr]| {
+            cidrs
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        info!("Allow: [{}], deny: [{}]", join(&allow), join(&deny));
+    }
+    let data_server = match server_addr {
+        Some(address) => DataServer::Fixed {
+            address,
+            proxy_protocol: send_proxy_protocol,
+            backends: backend_map,
+        },
+        None => DataServer::Socks5,
+    };
+    let shadow_server = Rc::new(ShadowTlsServer::new(
+        tls_addr,
+        data_server,
+        passwords,
+        sni_map,
+        opts.clone(),
+        shared.metrics.clone(),
+        shared.total_limits.clone(),
+    ));
+    let listeners = bind_listeners(&listen, worker, &opts)?;
+    let filter = IpFilter::new(allow, deny);
+    let header_timeout = Duration::from_secs(opts.handshake_timeout);
+    serve(
+        listeners,
+        worker,
+        &opts,
+        shared,
+        filter,
+        move |conn, addr| {
+            let server = shadow_server.clone();
+            async move {
+                // The header is stripped before the ClientHello is read.
+                match (conn, accept_proxy_protocol) {
+                    (Conn::Tcp(conn), false) => server.relay(conn, addr).await,
+                    (Conn::Unix(conn), false) => server.relay(conn, addr).await,
+                    (Conn::Tcp(conn), true) => {
+                        let (conn, addr) = accept_proxy_header(conn, addr, header_timeout).await?;
+                        server.relay(conn, addr).await
+                    }
+                    (Conn::Unix(conn), true) => {
+                        let (conn, addr) = accept_proxy_header(conn, addr, header_timeout).await?;
+                        server.relay(conn, addr).await
+                    }
+                }
             }
-            Err(e) => {
-                error!("Accept failed: {e}");
+        },
+    )
+    .await
+}
+
+/// Bind listen addresses for a worker, or adopt the sockets passed by systemd socket
+/// activation instead if any.
+/// Tcp listeners of all workers share the port by SO_REUSEPORT, which unix sockets do
+/// not support, so unix sockets are listened by the first worker only.
+fn bind_listeners(listen: &[String], worker: usize, opts: &Opts) -> anyhow::Result<Vec<Listener>> {
+    let listeners = match listener::activated_fds() {
+        Some(fds) => {
+            if worker == 0 {
+                info!(
+                    "Using {} sockets from systemd socket activation instead of listen addresses",
+                    fds.len()
+                );
+            }
+            fds.into_iter()
+                .map(|fd| {
+                    Listener::inherited(fd, opts).with_context(|| format!("adopt fd {fd} failed"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        }
+        None => {
+            match Listener::max_backlog() {
+                Some(max) if worker == 0 && opts.backlog > max => warn!(
+                    "Backlog {} is capped to {max} by net.core.somaxconn",
+                    opts.backlog
+                ),
+                _ => (),
+            }
+            listen
+                .iter()
+                .filter(|addr| worker == 0 || !Listener::is_unix(addr))
+                .map(|addr| {
+                    Listener::bind(addr, opts).map_err(|e| {
+                        match Listener::privileged_port_hint(addr, &e) {
+                            Some(hint) => anyhow::anyhow!("bind {addr} failed: {e}\nHint: {hint}"),
+                            None => anyhow::Error::new(e).context(format!("bind {addr} failed")),
+                        }
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        }
+    };
+    if worker == 0 {
+        for (addr, v6only) in listeners.iter().filter_map(Listener::v6only) {
+            info!("Listening on {addr} with IPV6_V6ONLY {v6only}");
+        }
+    }
+    Ok(listeners)
+}
+
+/// Accept connections on all listeners and spawn a relay task for each of them until
+/// shutdown is triggered, then wait for the in-flight relays at most `shutdown_timeout`.
+async fn serve<F, Fut>(
+    listeners: Vec<Listener>,
+    worker: usize,
+    opts: &Opts,
+    shared: Shared,
+    filter: IpFilter,
+    relay: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(Conn, PeerAddr) -> Fut + 'static,
+    Fut: Future<Output = Result<Relayed, RelayError>> + 'static,
+{
+    let Shared {
+        shutdown,
+        metrics,
+        health,
+        ready,
+        privileges_dropped,
+        heartbeats,
+        access_log,
+        ..
+    } = shared;
+    // No connection is accepted with the privileges to bind.
+    let ready = move || {
+        let _ = ready.send(());
+        if let Some(barrier) = privileges_dropped {
+            barrier.wait();
+        }
+    };
+    if listeners.is_empty() {
+        ready();
+        return Ok(());
+    }
+    if let Some(metrics_listen) = opts.metrics_listen.as_ref() {
+        let metrics_listener = TcpListener::bind(metrics_listen)?;
+        monoio::spawn(metrics::serve(metrics_listener, metrics.clone(), health));
+    }
+    if let Some(admin_listen) = opts.admin_listen.as_ref() {
+        let admin_listener = TcpListener::bind(admin_listen)?;
+        monoio::spawn(metrics::serve_admin(admin_listener, metrics.clone()));
+    }
+    ready();
+    if let Some(heartbeats) = heartbeats {
+        monoio::spawn(async move { heartbeats.beat(worker).await });
+    }
+    let acceptor = Rc::new(Acceptor {
+        worker,
+        opts: opts.clone(),
+        metrics,
+        access_log,
+        filter,
+        relay,
+        active: Rc::new(Cell::new(0)),
+        accepted: Cell::new(0),
+        completed: Rc::new(Cell::new(0)),
+        rejected: Cell::new(0),
+        last_reject_log: Cell::new(None),
+    });
+    if opts.stats_interval != 0 {
+        let interval = Duration::from_secs(opts.stats_interval);
+        monoio::spawn(acceptor.clone().log_stats(worker, interval));
+    }
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            if let (true, Listener::Tcp(listener)) = (opts.fast_open, &listener) {
+                set_fast_open_listener(listener);
             }
+            monoio::spawn(acceptor.clone().accept_loop(listener, shutdown.clone()))
+        })
+        .collect();
+    let mut result = Ok(());
+    for accept_loop in accept_loops {
+        if let Err(e) = accept_loop.await {
+            result = Err(e).context("accept failed");
+        }
+    }
+
+    let active = &acceptor.active;
+    info!(
+        "Shutting down, waiting for {} connections to finish",
+        active.get()
+    );
+    let drained = monoio::time::timeout(Duration::from_secs(opts.shutdown_timeout), async {
+        while active.get() != 0 {
+            monoio::time::sleep(Duration::from_millis(100)).await;
         }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Shutdown timeout elapsed, aborting {} connections",
+            active.get()
+        );
+    }
+    result
+}
+
+/// Acceptor is shared by the accept loops of all listeners on a worker thread.
+struct Acceptor<F> {
+    worker: usize,
+    opts: Opts,
+    metrics: Arc<Metrics>,
+    access_log: Option<Arc<AccessLog>>,
+    filter: IpFilter,
+    relay: F,
+    active: Rc<Cell<usize>>,
+    accepted: Cell<u64>,
+    completed: Rc<Cell<u64>>,
+    // Rejections are logged at most once per REJECT_LOG_INTERVAL.
+    rejected: Cell<u64>,
+    last_reject_log: Cell<Option<Instant>>,
+}
+
+impl<F, Fut> Acceptor<F>
+where
+    F: Fn(Conn, PeerAddr) -> Fut,
+    Fut: Future<Output = Result<Relayed, RelayError>> + 'static,
+{
+    const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Accept until shutdown, or a fatal accept error which is returned.
+    async fn accept_loop(
+        self: Rc<Self>,
+        mut listener: Listener,
+        shutdown: ShutdownSignal,
+    ) -> std::io::Result<()> {
+        // Kept across iterations so the wakeup is registered once.
+        let wait = shutdown.wait();
+        monoio::pin!(wait);
+        let mut backoff = Backoff::default();
+        let liveness = &self.metrics.liveness;
+        loop {
+            // Polled to completion across liveness beats: monoio does not cancel a dropped
+            // io_uring accept, which would take a later connection and leak it.
+            let accept = listener.accept();
+            monoio::pin!(accept);
+            let accepted = loop {
+                monoio::select! {
+                    _ = &mut wait => return Ok(()),
+                    _ = monoio::time::sleep(liveness.interval()) => liveness.beat(self.worker),
+                    accepted = &mut accept => break accepted,
+                }
+            };
+            match accepted {
+                Ok((conn, addr)) => {
+                    backoff.reset();
+                    liveness.accepted(self.worker);
+                    self.handle(conn, addr);
+                }
+                Err(e) => match AcceptError::classify(&e) {
+                    AcceptError::Connection => {
+                        debug!("Accept failed: {e}");
+                    }
+                    AcceptError::Resource => {
+                        let delay = backoff.next_delay();
+                        error!("Accept failed: {e}, retry in {delay:?}");
+                        monoio::select! {
+                            _ = &mut wait => break,
+                            _ = monoio::time::sleep(delay) => {}
+                        }
+                    }
+                    AcceptError::Fatal => {
+                        error!("Accept failed: {e}, stop listening");
+                        liveness.stop(self.worker);
+                        return Err(e);
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Log connection stats of this worker every interval until the runtime exits.
+    async fn log_stats(self: Rc<Self>, worker: usize, interval: Duration) {
+        loop {
+            monoio::time::sleep(interval).await;
+            info!(
+                worker,
+                accepted = self.accepted.get(),
+                active = self.active.get(),
+                completed = self.completed.get(),
+                inbound = self.metrics.bytes_inbound.load(Ordering::Relaxed),
+                outbound = self.metrics.bytes_outbound.load(Ordering::Relaxed),
+                "Worker stats"
+            );
+        }
+    }
+
+    /// Close a connection refused before relaying, with RST if --reject-with rst.
+    fn reject(&self, conn: Conn) {
+        if let (RejectWith::Rst, Conn::Tcp(conn)) = (self.opts.reject_with, &conn) {
+            reset_on_close(conn);
+        }
+    }
+
+    fn handle(&self, mut conn: Conn, addr: PeerAddr) {
+        let Self { opts, metrics, .. } = self;
+        let id = next_connection_id();
+        let span = connection_span(id);
+        let _entered = span.enter();
+        if !self.filter.permits(&addr) {
+            debug!(peer = %addr, "Denied a connection");
+            Metrics::inc(&metrics.denied);
+            self.reject(conn);
+            return;
+        }
+        info!(peer = %addr, "Accepted a connection");
+        Metrics::inc(&metrics.accepted);
+        let current = metrics.active.fetch_add(1, Ordering::Relaxed) + 1;
+        if matches!(opts.max_connections, Some(max) if current > max) {
+            Metrics::dec(&metrics.active);
+            Metrics::inc(&metrics.rejected);
+            self.reject(conn);
+            self.rejected.set(self.rejected.get() + 1);
+            if self
+                .last_reject_log
+                .get()
+                .map_or(true, |t| t.elapsed() >= Self::REJECT_LOG_INTERVAL)
+            {
+                warn!(
+                    "Rejected {} connections: max connections {} reached",
+                    self.rejected.get(),
+                    opts.max_connections.unwrap_or_default()
+                );
+                self.rejected.set(0);
+                self.last_reject_log.set(Some(Instant::now()));
+            }
+            return;
+        }
+        if let Conn::Tcp(conn) = &mut conn {
+            mod_tcp_conn(conn, opts, Direction::Inbound);
+        }
+        metrics.events.accepted(id, &addr);
+        let fut = InConnection::new(id, (self.relay)(conn, addr));
+        let active = self.active.clone();
+        let completed = self.completed.clone();
+        let metrics = metrics.clone();
+        let access_log = self.access_log.clone();
+        let start = Instant::now();
+        self.accepted.set(self.accepted.get() + 1);
+        active.set(active.get() + 1);
+        monoio::spawn(
+            async move {
+                let (result, sni) = fut.await;
+                match &result {
+                    Ok(relayed) => relayed.log(&addr),
+                    Err(e) => e.log(&addr),
+                }
+                metrics.events.closed(id, &addr, &result);
+                if let Some(access_log) = &access_log {
+                    access_log.log(&addr, sni.as_deref(), start.elapsed(), &result);
+                }
+                active.set(active.get() - 1);
+                completed.set(completed.get() + 1);
+                Metrics::dec(&metrics.active);
+            }
+            .instrument(span.clone()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_after_liveness_beat() {
+        // io_uring if available, where a dropped accept is not cancelled.
+        let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let opts = Opts {
+                liveness_timeout: 4,
+                ..Default::default()
+            };
+            let metrics = Arc::new(Metrics {
+                liveness: Liveness::new(1, Duration::from_secs(4)),
+                ..Default::default()
+            });
+            let listener = Listener::bind("127.0.0.1:0", &opts).unwrap();
+            let addr = match &listener {
+                Listener::Tcp(listener) => listener.local_addr().unwrap(),
+                _ => unreachable!("bound a tcp address"),
+            };
+            let relayed = Rc::new(Cell::new(0));
+            let counter = relayed.clone();
+            let acceptor = Rc::new(Acceptor {
+                worker: 0,
+                opts,
+                metrics: metrics.clone(),
+                access_log: None,
+                filter: IpFilter::default(),
+                relay: move |_, _| {
+                    counter.set(counter.get() + 1);
+                    async { Ok(Relayed::default()) }
+                },
+                active: Rc::new(Cell::new(0)),
+                accepted: Cell::new(0),
+                completed: Rc::new(Cell::new(0)),
+                rejected: Cell::new(0),
+                last_reject_log: Cell::new(None),
+            });
+            monoio::spawn(acceptor.accept_loop(listener, ShutdownSignal::never()));
+            // Idle past a liveness beat, every interval is a second.
+            monoio::time::sleep(Duration::from_millis(1500)).await;
+            assert!(metrics.liveness.stalled().is_none());
+            let _conn = monoio::net::TcpStream::connect(addr).await.unwrap();
+            for _ in 0..100 {
+                if relayed.get() != 0 {
+                    break;
+                }
+                monoio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(relayed.get(), 1);
+        });
     }
 }
@@ -0,0 +1,544 @@
+//! Prometheus metrics.
+
+use std::{
+    cell::Cell,
+    fmt::Write,
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    events::Events,
+    listener::{AcceptError, Backoff, PeerAddr},
+    upstream::Health,
+    util::timeout_or_shutdown,
+};
+
+// Time to read a request and write its response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Label of the names beyond the --sni-metrics limit and of handshakes without sni.
+const OTHER_SNI: &str = "other";
+
+/// Metrics shared by all worker threads.
+#[derive(Default)]
+pub struct Metrics {
+    pub accepted: AtomicU64,
+    pub active: AtomicU64,
+    /// Connections closed at once for reaching max connections.
+    pub rejected: AtomicU64,
+    /// Connections closed at once for peer address denied by --allow and --deny.
+    pub denied: AtomicU64,
+    /// Bytes read from accepted connections and relayed to remote.
+    pub bytes_inbound: AtomicU64,
+    /// Bytes read from remote and relayed to accepted connections.
+    pub bytes_outbound: AtomicU64,
+    /// Handshakes finished with the handshake server.
+    pub handshakes: AtomicU64,
+    /// Handshakes which waited for --max-pending-handshakes.
+    pub queued_handshakes: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    pub bad_password: AtomicU64,
+    pub sni: SniMetrics,
+    pub events: Events,
+    pub liveness: Liveness,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Render metrics in prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            );
+        };
+        metric(
+            "shadow_tls_accepted_connections_total",
+            "counter",
+            "Total accepted connections.",
+            &self.accepted,
+        );
+        metric(
+            "shadow_tls_active_connections",
+            "gauge",
+            "Connections being relayed.",
+            &self.active,
+        );
+        metric(
+            "shadow_tls_rejected_connections_total",
+            "counter",
+            "Connections rejected for reaching max connections.",
+            &self.rejected,
+        );
+        metric(
+            "shadow_tls_denied_connections_total",
+            "counter",
+            "Connections denied by peer address.",
+            &self.denied,
+        );
+        metric(
+            "shadow_tls_inbound_bytes_total",
+            "counter",
+            "Bytes relayed from accepted connections.",
+            &self.bytes_inbound,
+        );
+        metric(
+            "shadow_tls_outbound_bytes_total",
+            "counter",
+            "Bytes relayed to accepted connections.",
+            &self.bytes_outbound,
+        );
+        metric(
+            "shadow_tls_handshakes_total",
+            "counter",
+            "Tls handshakes finished with the handshake server.",
+            &self.handshakes,
+        );
+        metric(
+            "shadow_tls_queued_handshakes_total",
+            "counter",
+            "Handshakes which waited for others to finish first.",
+            &self.queued_handshakes,
+        );
+        metric(
+            "shadow_tls_handshake_failures_total",
+            "counter",
+            "Failed or invalid tls handshakes.",
+            &self.handshake_failures,
+        );
+        metric(
+            "shadow_tls_bad_password_total",
+            "counter",
+            "Connections rejected for hmac mismatch.",
+            &self.bad_password,
+        );
+        self.sni.render(&mut out);
+        self.liveness.render(&mut out);
+        out
+    }
+}
+
+/// When the accept loops of each worker last ran, so a worker which stopped accepting is
+/// noticed while the others keep the process up.
+#[derive(Default)]
+pub struct Liveness {
+    workers: Vec<WorkerLiveness>,
+    timeout: Duration,
+}
+
+/// Unix seconds of the last accepted connection and of the last run of the accept loops,
+/// 0 if never.
+#[derive(Default)]
+struct WorkerLiveness {
+    last_accept: AtomicU64,
+    last_beat: AtomicU64,
+    /// An accept loop stopped for a fatal error.
+    stopped: AtomicBool,
+}
+
+impl Liveness {
+    /// Liveness of workers considered stalled after not beating for timeout.
+    pub fn new(workers: usize, timeout: Duration) -> Self {
+        Self {
+            workers: (0..workers).map(|_| WorkerLiveness::default()).collect(),
+            timeout,
+        }
+    }
+
+    /// Accept loops beat at least this often while running.
+    pub fn interval(&self) -> Duration {
+        self.timeout / 4
+    }
+
+    pub fn accepted(&self, worker: usize) {
+        let now = unix_now();
+        if let Some(w) = self.workers.get(worker) {
+            w.last_accept.store(now, Ordering::Relaxed);
+            w.last_beat.store(now, Ordering::Relaxed);
+        }
+    }
+
+    pub fn beat(&self, worker: usize) {
+        if let Some(w) = self.workers.get(worker) {
+            w.last_beat.store(unix_now(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn stop(&self, worker: usize) {
+        if let Some(w) = self.workers.get(worker) {
+            w.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// First worker whose accept loop stopped, or which beat before but not within the
+    /// timeout. Workers which never beat are starting or have no listeners.
+    pub fn stalled(&self) -> Option<usize> {
+        self.stalled_at(unix_now())
+    }
+
+    fn stalled_at(&self, now: u64) -> Option<usize> {
+        self.workers.iter().position(|w| {
+            let last_beat = w.last_beat.load(Ordering::Relaxed);
+            w.stopped.load(Ordering::Relaxed)
+                || (last_beat != 0 && now.saturating_sub(last_beat) > self.timeout.as_secs())
+        })
+    }
+
+    fn render(&self, out: &mut String) {
+        if self.workers.is_empty() {
+            return;
+        }
+        let mut metric = |name: &str, help: &str, value: fn(&WorkerLiveness) -> u64| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n");
+            for (worker, w) in self.workers.iter().enumerate() {
+                let _ = writeln!(out, "{name}{{worker=\"{worker}\"}} {}", value(w));
+            }
+        };
+        metric(
+            "shadow_tls_worker_last_accept_timestamp_seconds",
+            "Unix time of the last connection accepted by the worker.",
+            |w| w.last_accept.load(Ordering::Relaxed),
+        );
+        metric(
+            "shadow_tls_worker_last_heartbeat_timestamp_seconds",
+            "Unix time the accept loops of the worker last ran.",
+            |w| w.last_beat.load(Ordering::Relaxed),
+        );
+        metric(
+            "shadow_tls_worker_accept_stopped",
+            "1 if an accept loop of the worker stopped for a fatal error.",
+            |w| w.stopped.load(Ordering::Relaxed) as u64,
+        );
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Counters of the connections using one sni.
+#[derive(Default)]
+pub struct SniCounters {
+    pub handshakes: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    pub bytes_inbound: AtomicU64,
+    pub bytes_outbound: AtomicU64,
+}
+
+/// Counters by sni, disabled without a limit. Names are counted in the order seen until
+/// the limit, so a flood of random names can not blow up the metrics.
+#[derive(Default)]
+pub struct SniMetrics {
+    limit: Option<usize>,
+    names: Mutex<Vec<(String, Arc<SniCounters>)>>,
+    other: Arc<SniCounters>,
+}
+
+impl SniMetrics {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit: limit.map(|limit| limit as usize),
+            ..Default::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// Counters of name, None if disabled.
+    pub fn counters(&self, name: Option<&str>) -> Option<Arc<SniCounters>> {
+        let limit = self.limit?;
+        let name = match name {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Some(self.other.clone()),
+        };
+        let mut names = self.names.lock().unwrap();
+        if let Some((_, counters)) = names.iter().find(|(n, _)| *n == name) {
+            return Some(counters.clone());
+        }
+        if names.len() >= limit {
+            return Some(self.other.clone());
+        }
+        let counters = Arc::new(SniCounters::default());
+        names.push((name, counters.clone()));
+        Some(counters)
+    }
+
+    fn render(&self, out: &mut String) {
+        if !self.enabled() {
+            return;
+        }
+        let names = self.names.lock().unwrap();
+        let all: Vec<_> = names
+            .iter()
+            .map(|(name, counters)| (name.as_str(), counters))
+            .chain([(OTHER_SNI, &self.other)])
+            .collect();
+        let mut metric = |name: &str, help: &str, value: fn(&SniCounters) -> &AtomicU64| {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n");
+            for (sni, counters) in all.iter() {
+                let sni = sni.replace('\\', "\\\\").replace('"', "\\\"");
+                let value = value(counters).load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}{{sni=\"{sni}\"}} {value}");
+            }
+        };
+        metric(
+            "shadow_tls_sni_handshakes_total",
+            "Tls handshakes finished with the handshake server by sni.",
+            |c| &c.handshakes,
+        );
+        metric(
+            "shadow_tls_sni_handshake_failures_total",
+            "Failed or invalid tls handshakes by sni.",
+            |c| &c.handshake_failures,
+        );
+        metric(
+            "shadow_tls_sni_inbound_bytes_total",
+            "Bytes relayed from accepted connections by sni.",
+            |c| &c.bytes_inbound,
+        );
+        metric(
+            "shadow_tls_sni_outbound_bytes_total",
+            "Bytes relayed to accepted connections by sni.",
+            |c| &c.bytes_outbound,
+        );
+    }
+}
+
+/// Bytes relayed in one direction of a connection, also added to a total of all
+/// connections and to the total of its sni if counted.
+pub struct ByteCounter<'a> {
+    total: &'a AtomicU64,
+    sni_total: Option<&'a AtomicU64>,
+    count: Cell<u64>,
+}
+
+impl<'a> ByteCounter<'a> {
+    pub fn new(total: &'a AtomicU64) -> Self {
+        Self {
+            total,
+            sni_total: None,
+            count: Cell::new(0),
+        }
+    }
+
+    pub fn with_sni(mut self, sni_total: Option<&'a AtomicU64>) -> Self {
+        self.sni_total = sni_total;
+        self
+    }
+
+    pub fn add(&self, n: u64) {
+        self.count.set(self.count.get() + n);
+        self.total.fetch_add(n, Ordering::Relaxed);
+        if let Some(sni_total) = self.sni_total {
+            sni_total.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.count.get()
+    }
+}
+
+/// Summary of a relayed connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Relayed {
+    pub inbound: u64,
+    pub outbound: u64,
+    pub duration: Duration,
+}
+
+impl Relayed {
+    pub fn log(&self, peer: &PeerAddr) {
+        tracing::info!(
+            %peer,
+            inbound = self.inbound,
+            outbound = self.outbound,
+            duration_ms = self.duration.as_millis() as u64,
+            "Relay finished"
+        );
+    }
+}
+
+/// Serve metrics over http on the given listener, with health of client upstreams if any.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, health: Option<Arc<Health>>) {
+    accept(listener, metrics, health, false).await
+}
+
+/// Serve /healthz, always ok, and /readyz, ok once a handshake with the handshake server
+/// finished while no worker stalled, over http on the given listener.
+pub async fn serve_admin(listener: TcpListener, metrics: Arc<Metrics>) {
+    accept(listener, metrics, None, true).await
+}
+
+async fn accept(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    health: Option<Arc<Health>>,
+    admin: bool,
+) {
+    let mut backoff = Backoff::default();
+    loop {
+        match listener.accept().await {
+            Ok((conn, _)) => {
+                backoff.reset();
+                let metrics = metrics.clone();
+                let health = health.clone();
+                monoio::spawn(async move {
+                    if let Err(e) = handle(conn, &metrics, health.as_deref(), admin).await {
+                        tracing::debug!("metrics request failed: {e}");
+                    }
+                });
+            }
+            Err(e) => match AcceptError::classify(&e) {
+                AcceptError::Connection => tracing::debug!("Metrics accept failed: {e}"),
+                AcceptError::Resource => {
+                    let delay = backoff.next_delay();
+                    tracing::error!("Metrics accept failed: {e}, retry in {delay:?}");
+                    monoio::time::sleep(delay).await;
+                }
+                AcceptError::Fatal => {
+                    tracing::error!("Metrics accept failed: {e}, stop listening");
+                    return;
+                }
+            },
+        }
+    }
+}
+
+async fn handle(
+    mut conn: TcpStream,
+    metrics: &Metrics,
+    health: Option<&Health>,
+    admin: bool,
+) -> std::io::Result<()> {
+    let fd = conn.as_raw_fd();
+    let respond = respond(&mut conn, metrics, health, admin);
+    timeout_or_shutdown(REQUEST_TIMEOUT, &[fd], respond).await??;
+    let _ = conn.shutdown().await;
+    Ok(())
+}
+
+async fn respond(
+    conn: &mut TcpStream,
+    metrics: &Metrics,
+    health: Option<&Health>,
+    admin: bool,
+) -> std::io::Result<()> {
+    // We only care about the request line, so one read is enough.
+    let buf = vec![0; 1024];
+    let (res, buf) = conn.read(buf).await;
+    let n = res?;
+    let request = &buf[..n];
+    let status = |status: &str, body: &str| {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    };
+    let response = match admin {
+        false if request.starts_with(b"GET /metrics ") => {
+            let mut body = metrics.render();
+            if let Some(health) = health {
+                body.push_str(&health.render());
+            }
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        true if request.starts_with(b"GET /healthz ") => status("200 OK", "ok\n"),
+        true if request.starts_with(b"GET /readyz ") => {
+            match (
+                metrics.handshakes.load(Ordering::Relaxed),
+                metrics.liveness.stalled(),
+            ) {
+                (_, Some(worker)) => status(
+                    "503 Service Unavailable",
+                    &format!("worker {worker} stopped accepting\n"),
+                ),
+                (0, None) => status("503 Service Unavailable", "no handshake finished yet\n"),
+                _ => status("200 OK", "ready\n"),
+            }
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let (res, _) = conn.write_all(response.into_bytes()).await;
+    res.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sni_metrics() {
+        assert!(SniMetrics::default().counters(Some("a.com")).is_none());
+        let sni = SniMetrics::new(Some(2));
+        Metrics::inc(&sni.counters(Some("a.com")).unwrap().handshakes);
+        Metrics::inc(&sni.counters(Some("A.com")).unwrap().handshakes);
+        Metrics::inc(&sni.counters(Some("b\"\\.com")).unwrap().handshake_failures);
+        Metrics::inc(&sni.counters(Some("c.com")).unwrap().handshakes);
+        Metrics::inc(&sni.counters(None).unwrap().handshakes);
+        let mut out = String::new();
+        sni.render(&mut out);
+        let handshakes: Vec<_> = out
+            .lines()
+            .filter(|l| l.starts_with("shadow_tls_sni_handshakes_total{"))
+            .collect();
+        assert_eq!(
+            handshakes,
+            [
+                "shadow_tls_sni_handshakes_total{sni=\"a.com\"} 2",
+                "shadow_tls_sni_handshakes_total{sni=\"b\\\"\\\\.com\"} 0",
+                "shadow_tls_sni_handshakes_total{sni=\"other\"} 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_liveness() {
+        let liveness = Liveness::new(3, Duration::from_secs(60));
+        assert_eq!(liveness.interval(), Duration::from_secs(15));
+        // Workers which never beat are not stalled.
+        assert_eq!(liveness.stalled(), None);
+        liveness.accepted(0);
+        liveness.beat(1);
+        let now = liveness.workers[0].last_accept.load(Ordering::Relaxed);
+        assert_eq!(liveness.stalled_at(now + 60), None);
+        assert_eq!(liveness.stalled_at(now + 61), Some(0));
+        liveness.stop(2);
+        assert_eq!(liveness.stalled(), Some(2));
+
+        let mut out = String::new();
+        liveness.render(&mut out);
+        assert!(out.contains(&format!(
+            "shadow_tls_worker_last_accept_timestamp_seconds{{worker=\"0\"}} {now}\n"
+        )));
+        assert!(out.contains("shadow_tls_worker_last_accept_timestamp_seconds{worker=\"1\"} 0\n"));
+        assert!(out.contains("shadow_tls_worker_accept_stopped{worker=\"2\"} 1\n"));
+    }
+}
@@ -0,0 +1,172 @@
+//! Service notifications of systemd units with Type=notify: READY=1 once all workers
+//! listen, STOPPING=1 on shutdown and WATCHDOG=1 while every worker keeps running and
+//! accepting.
+
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::{metrics::Liveness, signal::ShutdownSignal};
+
+// Shutdown is noticed within this when no watchdog interval is shorter.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Notifier sends to the socket systemd passes in NOTIFY_SOCKET.
+pub struct Notifier {
+    socket: Socket,
+    addr: SockAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Notifier of the service manager, None if not started by one with Type=notify.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        // WATCHDOG_PID names another process when set by a wrapper before exec.
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| {
+                std::env::var("WATCHDOG_PID")
+                    .map_or(true, |pid| pid == std::process::id().to_string())
+            })
+            .and_then(|usec| usec.parse().ok())
+            .filter(|&usec| usec != 0)
+            .map(Duration::from_micros);
+        match Self::new(Path::new(&path), watchdog) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                tracing::warn!("Open NOTIFY_SOCKET {path:?} failed: {e}");
+                None
+            }
+        }
+    }
+
+    /// Notifier of path, with @ for a linux abstract socket.
+    pub fn new(path: &Path, watchdog: Option<Duration>) -> std::io::Result<Self> {
+        let addr = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(name) => SockAddr::unix(format!("\0{name}"))?,
+            None => SockAddr::unix(path)?,
+        };
+        let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)?;
+        Ok(Self {
+            socket,
+            addr,
+            watchdog,
+        })
+    }
+
+    /// Interval systemd expects WATCHDOG=1 within, if the unit sets WatchdogSec.
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to(state.as_bytes(), &self.addr) {
+            tracing::warn!("Notify {} failed: {e}", state.trim_end());
+        }
+    }
+
+    /// Ping the watchdog at half its interval while heartbeats advance and no accept loop
+    /// stalled, until shutdown which is notified as STOPPING=1.
+    pub fn run(
+        &self,
+        shutdown: &ShutdownSignal,
+        heartbeats: Option<&Heartbeats>,
+        liveness: &Liveness,
+    ) {
+        let watchdog = self.watchdog.zip(heartbeats);
+        let tick = watchdog.map_or(POLL_INTERVAL, |(watchdog, _)| {
+            (watchdog / 2).min(POLL_INTERVAL)
+        });
+        let mut last = heartbeats.map(Heartbeats::snapshot);
+        let mut waited = Duration::ZERO;
+        while !shutdown.is_triggered() {
+            std::thread::sleep(tick);
+            waited += tick;
+            if let (Some((watchdog, heartbeats)), Some(last)) = (watchdog, last.as_mut()) {
+                if waited >= watchdog / 2 {
+                    waited = Duration::ZERO;
+                    let now = heartbeats.snapshot();
+                    match (stalled(last, &now), liveness.stalled()) {
+                        (Some(worker), _) => {
+                            tracing::warn!("Worker {worker} stalled, skip watchdog ping")
+                        }
+                        (None, Some(worker)) => {
+                            tracing::warn!("Worker {worker} stopped accepting, skip watchdog ping")
+                        }
+                        (None, None) => self.notify("WATCHDOG=1"),
+                    }
+                    *last = now;
+                }
+            }
+        }
+        self.notify("STOPPING=1");
+    }
+}
+
+/// Heartbeats count timer ticks of each worker, so a wedged worker stops the watchdog
+/// pings rather than being hidden by a healthy thread.
+pub struct Heartbeats {
+    beats: Vec<AtomicU64>,
+    interval: Duration,
+}
+
+impl Heartbeats {
+    /// Heartbeats of workers beating at a quarter of the watchdog interval.
+    pub fn new(workers: usize, watchdog: Duration) -> Self {
+        Self {
+            beats: (0..workers).map(|_| AtomicU64::new(0)).collect(),
+            interval: watchdog / 4,
+        }
+    }
+
+    /// Beat for worker until its runtime is dropped.
+    pub async fn beat(&self, worker: usize) {
+        loop {
+            self.beats[worker].fetch_add(1, Ordering::Relaxed);
+            monoio::time::sleep(self.interval).await;
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u64> {
+        self.beats
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// First worker which beat before but not since last. Workers which never beat have no
+/// listeners and are done.
+fn stalled(last: &[u64], now: &[u64]) -> Option<usize> {
+    last.iter()
+        .zip(now)
+        .position(|(&last, &now)| last != 0 && last == now)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("shadow-tls-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(&path, None).unwrap();
+        notifier.notify("READY=1");
+        let mut buf = [0; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(stalled(&[0, 1, 2], &[0, 2, 3]), None);
+        assert_eq!(stalled(&[0, 1, 2], &[0, 2, 2]), Some(2));
+    }
+}
@@ -0,0 +1,140 @@
+//! Connections to the server made ahead of demand.
+//!
+//! Only the tcp connect is done ahead, each pooled connection is handed to exactly one
+//! local connection, which does the tls handshake and relays through it as usual. The
+//! handshake can not be done ahead: the server takes everything the handshake server sends
+//! until the hmac frame into the hmac, and tls 1.3 servers send session tickets after the
+//! handshake, which would break the hmac of a tunnel waiting in the pool.
+//! The server waits for the handshake within its handshake_timeout, connections are
+//! dropped after max_age to stay within it.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    future::poll_fn,
+    os::unix::io::AsRawFd,
+    task::{Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use monoio::net::TcpStream;
+
+pub struct Pool {
+    size: usize,
+    max_age: Duration,
+    /// Connections with when connecting them started, the server counts its handshake
+    /// timeout from about then.
+    idle: RefCell<VecDeque<(Instant, TcpStream)>>,
+    waker: Cell<Option<Waker>>,
+}
+
+impl Pool {
+    pub fn new(size: usize, max_age: Duration) -> Self {
+        Self {
+            size,
+            max_age,
+            idle: RefCell::new(VecDeque::with_capacity(size)),
+            waker: Cell::new(None),
+        }
+    }
+
+    /// Take the oldest connection still usable.
+    pub fn take(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.borrow_mut();
+        let stream = loop {
+            let (started, stream) = idle.pop_front()?;
+            if started.elapsed() < self.max_age && is_open(&stream) {
+                break stream;
+            }
+        };
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Some(stream)
+    }
+
+    /// Add a connection which started connecting at started.
+    pub fn put(&self, started: Instant, stream: TcpStream) {
+        self.idle.borrow_mut().push_back((started, stream));
+    }
+
+    /// Wait until the pool is short of a connection, dropping expired ones meanwhile.
+    pub async fn wanted(&self) {
+        loop {
+            let oldest = {
+                let mut idle = self.idle.borrow_mut();
+                while matches!(idle.front(), Some((started, _)) if started.elapsed() >= self.max_age)
+                {
+                    idle.pop_front();
+                }
+                if idle.len() < self.size {
+                    return;
+                }
+                idle.front().map(|(started, _)| *started)
+            };
+            let expiry = oldest.map_or_else(Instant::now, |started| started + self.max_age);
+            let taken = poll_fn(|cx| match self.idle.borrow().len() < self.size {
+                true => Poll::Ready(()),
+                false => {
+                    self.waker.set(Some(cx.waker().clone()));
+                    Poll::Pending
+                }
+            });
+            let _ = monoio::time::timeout_at(expiry.into(), taken).await;
+        }
+    }
+}
+
+/// Whether the server has not closed the connection.
+fn is_open(stream: &TcpStream) -> bool {
+    let mut buf = [0_u8; 1];
+    let n = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    n > 0 || (n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio::{
+        io::AsyncWriteRent,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let pool = Pool::new(3, Duration::from_secs(10));
+            let mut peers = Vec::new();
+            for age in [20, 0, 0] {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let (peer, peer_addr) = listener.accept().await.unwrap();
+                peers.push((peer, peer_addr));
+                pool.put(Instant::now() - Duration::from_secs(age), stream);
+            }
+            // Expired connection is dropped, short of one without it.
+            pool.wanted().await;
+            assert_eq!(pool.idle.borrow().len(), 2);
+            let _ = peers[1].0.shutdown().await;
+            monoio::time::sleep(Duration::from_millis(50)).await;
+            // Closed by peer is skipped.
+            let stream = pool.take().unwrap();
+            assert_eq!(stream.local_addr().unwrap(), peers[2].1);
+            assert!(pool.take().is_none());
+            pool.wanted().await;
+        });
+    }
+}
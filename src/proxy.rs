@@ -0,0 +1,257 @@
+//! PROXY protocol headers telling the original client address.
+//! Spec: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::io::AsRawFd,
+    time::Duration,
+};
+
+use monoio::io::AsyncReadRent;
+
+use crate::{listener::PeerAddr, stream::PrefixedReadStream, util::timeout_or_shutdown};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_PROXY: u8 = 0x21;
+const V2_LOCAL: u8 = 0x20;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+const V2_HEADER_SIZE: usize = 16;
+const V1_PREFIX: &[u8] = b"PROXY ";
+// Max length of a v1 header line including CRLF.
+const V1_MAX_SIZE: usize = 107;
+
+/// Encode a PROXY protocol v2 header of a tcp connection from src to dst.
+/// Addresses are sent as IPv4 if both of them are IPv4 or IPv4-mapped, otherwise as IPv6.
+/// A LOCAL header is encoded if any of them is unknown, like for unix socket peers.
+pub fn encode_v2(src: Option<SocketAddr>, dst: Option<SocketAddr>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let (src, dst) = match (src, dst) {
+        (Some(src), Some(dst)) => (src, dst),
+        _ => {
+            header.extend_from_slice(&[V2_LOCAL, V2_UNSPEC, 0, 0]);
+            return header;
+        }
+    };
+    let mut addrs = Vec::with_capacity(36);
+    match (to_ipv4(src.ip()), to_ipv4(dst.ip())) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            header.extend_from_slice(&[V2_PROXY, V2_TCP4]);
+            addrs.extend_from_slice(&src_ip.octets());
+            addrs.extend_from_slice(&dst_ip.octets());
+        }
+        _ => {
+            header.extend_from_slice(&[V2_PROXY, V2_TCP6]);
+            addrs.extend_from_slice(&to_ipv6(src.ip()).octets());
+            addrs.extend_from_slice(&to_ipv6(dst.ip()).octets());
+        }
+    }
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+    header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addrs);
+    header
+}
+
+/// Read a PROXY protocol v1 or v2 header from stream within timeout.
+/// Returns the stream replaying data after the header, and the source address in header,
+/// or addr if the header carries no address like LOCAL and UNKNOWN ones.
+pub async fn accept<S: AsyncReadRent + AsRawFd>(
+    mut stream: S,
+    addr: PeerAddr,
+    timeout: Duration,
+) -> io::Result<(PrefixedReadStream<S>, PeerAddr)> {
+    let fd = stream.as_raw_fd();
+    let mut buf = Vec::new();
+    let (len, src) = timeout_or_shutdown(timeout, &[fd], async {
+        loop {
+            if let Some(parsed) = parse(&buf)? {
+                return Ok::<_, io::Error>(parsed);
+            }
+            let (res, chunk) = stream.read(Vec::with_capacity(256)).await;
+            match res? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => buf.extend_from_slice(&chunk),
+            }
+        }
+    })
+    .await??;
+    let addr = src.map_or(addr, PeerAddr::Tcp);
+    Ok((PrefixedReadStream::new(stream, buf[len..].to_vec()), addr))
+}
+
+/// Parse a header at the beginning of buf, returns its length and the source address in it,
+/// or None if more data is needed.
+fn parse(buf: &[u8]) -> io::Result<Option<(usize, Option<SocketAddr>)>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let n = buf.len().min(V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        if buf.len() < V2_HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = V2_HEADER_SIZE + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < len {
+            return Ok(None);
+        }
+        let body = &buf[V2_HEADER_SIZE..len];
+        let src = match (buf[12], buf[13]) {
+            (V2_LOCAL, _) => None,
+            (V2_PROXY, V2_TCP4) if body.len() >= 12 => {
+                let ip: [u8; 4] = body[..4].try_into().unwrap();
+                Some(SocketAddr::new(
+                    ip.into(),
+                    u16::from_be_bytes([body[8], body[9]]),
+                ))
+            }
+            (V2_PROXY, V2_TCP6) if body.len() >= 36 => {
+                let ip: [u8; 16] = body[..16].try_into().unwrap();
+                Some(SocketAddr::new(
+                    ip.into(),
+                    u16::from_be_bytes([body[32], body[33]]),
+                ))
+            }
+            (V2_PROXY, V2_TCP4 | V2_TCP6) => return Err(invalid("truncated v2 addresses")),
+            // Other families like unix sockets carry no ip address.
+            (V2_PROXY, _) => None,
+            _ => return Err(invalid("unknown v2 version or command")),
+        };
+        return Ok(Some((len, src)));
+    }
+    let n = buf.len().min(V1_PREFIX.len());
+    if buf[..n] != V1_PREFIX[..n] {
+        return Err(invalid("no PROXY protocol header"));
+    }
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_SIZE => end,
+        None if buf.len() < V1_MAX_SIZE => return Ok(None),
+        _ => return Err(invalid("v1 header too long")),
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| invalid("v1 header is not utf8"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let src = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        ["TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid v1 source ip"))?;
+            let port: u16 = sport
+                .parse()
+                .map_err(|_| invalid("invalid v1 source port"))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid("invalid v1 header")),
+    };
+    Ok(Some((end + 2, src)))
+}
+
+fn to_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_v2() {
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        let header = encode_v2(addr("1.2.3.4:1000"), addr("[::ffff:5.6.7.8]:443"));
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(
+            &header[12..],
+            &[0x21, 0x11, 0, 12, 1, 2, 3, 4, 5, 6, 7, 8, 0x03, 0xe8, 0x01, 0xbb]
+        );
+
+        let header = encode_v2(addr("[2001:db8::1]:1000"), addr("5.6.7.8:443"));
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(header[16], 0x20);
+        assert_eq!(&header[32..44], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        assert_eq!(header.len(), 16 + 36);
+
+        assert_eq!(
+            &encode_v2(None, addr("5.6.7.8:443"))[12..],
+            &[0x20, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        let header = encode_v2(addr("[2001:db8::1]:1000"), addr("5.6.7.8:443"));
+        let mut data = header.clone();
+        data.extend_from_slice(b"data");
+        assert_eq!(
+            parse(&data).unwrap(),
+            Some((header.len(), addr("[2001:db8::1]:1000")))
+        );
+        assert_eq!(parse(&data[..20]).unwrap(), None);
+        assert_eq!(parse(&encode_v2(None, None)).unwrap(), Some((16, None)));
+
+        let data = b"PROXY TCP4 1.2.3.4 5.6.7.8 1000 443\r\ndata";
+        assert_eq!(
+            parse(data).unwrap(),
+            Some((data.len() - 4, addr("1.2.3.4:1000")))
+        );
+        assert_eq!(parse(&data[..10]).unwrap(), None);
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), Some((15, None)));
+        assert!(parse(b"PROXY TCP4 1.2.3.4\r\n").is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(&[V1_PREFIX, &[b'x'; 200]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_accept_before_client_hello() {
+        use std::sync::Arc;
+
+        use monoio::{
+            io::AsyncWriteRentExt,
+            net::{TcpListener, TcpStream},
+        };
+
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let name = "www.example.com".try_into().unwrap();
+        let mut conn = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut client_hello = Vec::new();
+        conn.write_tls(&mut client_hello).unwrap();
+        let client = "1.2.3.4:1000".parse().unwrap();
+        let mut data = encode_v2(Some(client), "5.6.7.8:443".parse().ok());
+        data.extend_from_slice(&client_hello);
+
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (res, _) = stream.write_all(data).await;
+            res.unwrap();
+            let (conn, lb) = listener.accept().await.unwrap();
+            let (mut conn, addr) = accept(conn, PeerAddr::Tcp(lb), Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(addr.to_string(), PeerAddr::Tcp(client).to_string());
+            let record = crate::sni::read_client_hello(&mut conn, 16384)
+                .await
+                .unwrap();
+            assert_eq!(record, client_hello);
+            assert_eq!(crate::sni::server_name(&record), Some("www.example.com"));
+        });
+    }
+}
@@ -1,104 +1,569 @@
-use std::net::ToSocketAddrs;
+use std::{
+    cell::Cell,
+    os::unix::io::AsRawFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use monoio::{
     buf::{IoBuf, Slice, SliceMut},
-    io::{AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt, Splitable},
-    net::TcpStream,
+    io::{AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt, Split, Splitable},
 };
 
+#[cfg(target_os = "linux")]
+use crate::util::{dup_tcp_stream, splice_without_application_data};
 use crate::{
-    stream::{HashedWriteStream, HmacHandler},
+    backend::{self, Backend},
+    dns::Resolver,
+    error::RelayError,
+    limit::{Limiter, Semaphore, TotalLimits},
+    listener::PeerAddr,
+    metrics::{ByteCounter, Metrics, Relayed},
+    proxy,
+    sni::{self, SniRoute},
+    socks5::Address,
+    stream::{HashedWriteStream, HmacHandler, PrefixedReadStream},
     util::{
-        copy_until_eof, copy_with_application_data, copy_without_application_data, mod_tcp_conn,
-        ErrGroup, FirstRetGroup, APPLICATION_DATA,
+        connect, connect_until, copy_plain, copy_until_eof, copy_with_application_data,
+        copy_without_application_data, handshake_permit, mod_tcp_conn, relay_plain,
+        relay_until_expired, timeout_or_shutdown, Direction, ErrGroup, FirstRetGroup,
+        FutureOrOutput, ShutdownGuard, APPLICATION_DATA,
     },
-    Opts,
+    Opts, Protocol,
 };
 
+/// Where server relays data to after the handshake.
+pub enum DataServer<A> {
+    /// A fixed data server, told the client address by a PROXY protocol v2 header if
+    /// proxy_protocol is set. Clients sending a routing tag are relayed to the backend
+    /// of the tag instead.
+    Fixed {
+        address: A,
+        proxy_protocol: bool,
+        backends: Vec<Backend>,
+    },
+    /// The socks5 target sent in the first frame.
+    Socks5,
+}
+
 /// ShadowTlsServer.
 pub struct ShadowTlsServer<RA, RB> {
     handshake_address: RA,
-    data_address: RB,
-    password: String,
-    opts: Opts,
+    data_server: DataServer<RB>,
+    passwords: Vec<String>,
+    /// Handshake servers chosen by SNI, handshake_address is used if none matches.
+    sni_map: Vec<SniRoute>,
+    resolver: Resolver,
+    pub(crate) opts: Opts,
+    metrics: Arc<Metrics>,
+    total_limits: Option<Arc<TotalLimits>>,
+    /// Handshakes in progress at once if capped.
+    handshake_permits: Option<Semaphore>,
 }
 
 impl<HA, DA> ShadowTlsServer<HA, DA> {
-    pub fn new(handshake_address: HA, data_address: DA, password: String, opts: Opts) -> Self {
+    pub fn new(
+        handshake_address: HA,
+        data_server: DataServer<DA>,
+        passwords: Vec<String>,
+        sni_map: Vec<SniRoute>,
+        opts: Opts,
+        metrics: Arc<Metrics>,
+        total_limits: Option<Arc<TotalLimits>>,
+    ) -> Self {
         Self {
             handshake_address,
-            data_address,
-            password,
+            data_server,
+            passwords,
+            sni_map,
+            resolver: Resolver::new(opts.clone()),
+            handshake_permits: opts
+                .max_pending_handshakes
+                .map(|max| Semaphore::new(max as usize)),
             opts,
+            metrics,
+            total_limits,
         }
     }
 }
 
 impl<HA, DA> ShadowTlsServer<HA, DA>
 where
-    HA: ToSocketAddrs,
-    DA: ToSocketAddrs,
+    HA: AsRef<str>,
+    DA: AsRef<str>,
 {
-    pub async fn relay(&self, in_stream: TcpStream) -> anyhow::Result<()> {
-        let mut out_stream = TcpStream::connect(&self.handshake_address).await?;
-        mod_tcp_conn(&mut out_stream, true, self.opts.nodelay);
+    pub async fn relay<S>(
+        &self,
+        mut in_stream: S,
+        in_stream_addr: PeerAddr,
+    ) -> Result<Relayed, RelayError>
+    where
+        S: AsyncReadRent + AsyncWriteRent + Split + AsRawFd,
+    {
+        let start = Instant::now();
+        if let (true, DataServer::Fixed { address, .. }) =
+            (self.opts.plain_relay, &self.data_server)
+        {
+            let mut out_stream = self
+                .connect_with_retries(address.as_ref())
+                .await
+                .map_err(|e| RelayError::UpstreamConnect(e.into()))?;
+            mod_tcp_conn(&mut out_stream, &self.opts, Direction::Outbound);
+            let total_limits = self.total_limits.as_deref();
+            return relay_plain(
+                in_stream,
+                out_stream,
+                &self.opts,
+                &self.metrics,
+                total_limits,
+                start,
+            )
+            .await;
+        }
+        let in_fd = in_stream.as_raw_fd();
+        let mut guard = ShutdownGuard::default();
+        guard.watch(in_fd)?;
+        let permit = handshake_permit(self.handshake_permits.as_ref(), &self.opts, &self.metrics)
+            .await
+            .map_err(|_| RelayError::Timeout)?;
+        let local_addr = match self.data_server {
+            DataServer::Fixed {
+                proxy_protocol: true,
+                ..
+            } => socket2::SockRef::from(&in_stream)
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_socket()),
+            _ => None,
+        };
+        // Sni is read for routing, checking, counting or logging, unknown names go to the
+        // handshake server.
+        let read_sni = !self.sni_map.is_empty()
+            || self.opts.expected_sni.is_some()
+            || self.metrics.sni.enabled()
+            || self.opts.access_log.is_some();
+        let (handshake_address, client_hello, sni, server_name) = match read_sni {
+            false => (self.handshake_address.as_ref(), Vec::new(), None, None),
+            true => {
+                let client_hello = timeout_or_shutdown(
+                    Duration::from_secs(self.opts.handshake_timeout),
+                    &[in_fd],
+                    sni::read_client_hello(&mut in_stream, self.opts.max_clienthello_size as usize),
+                )
+                .await
+                .map_err(|_| RelayError::Timeout)?
+                .map_err(|e| RelayError::TlsHandshake(e.into()))?;
+                let server_name = sni::server_name(&client_hello);
+                let address = server_name
+                    .and_then(|name| sni::route(&self.sni_map, name))
+                    .unwrap_or_else(|| self.handshake_address.as_ref());
+                tracing::debug!(peer = %in_stream_addr, sni = ?server_name, "handshake server {address} chosen");
+                let sni = self.metrics.sni.counters(server_name);
+                let server_name = server_name.map(str::to_owned);
+                (address, client_hello, sni, server_name)
+            }
+        };
+        // Scanners not knowing the expected sni get the handshake server only.
+        let unexpected_sni = match &self.opts.expected_sni {
+            Some(expected) => {
+                !matches!(&server_name, Some(name) if name.eq_ignore_ascii_case(expected))
+            }
+            None => false,
+        };
+        // Delayed before telling authorized clients from probes, so both see the same timing.
+        if self.opts.response_jitter != 0 {
+            let jitter = monoio::utils::thread_rng_n(self.opts.response_jitter + 1);
+            monoio::time::sleep(Duration::from_millis(jitter as u64)).await;
+        }
+        let mut out_stream = self
+            .connect(handshake_address)
+            .await
+            .map_err(|e| RelayError::UpstreamConnect(e.into()))?;
+        mod_tcp_conn(&mut out_stream, &self.opts, Direction::Outbound);
         tracing::debug!("handshake server connected");
-        let mut in_stream = HashedWriteStream::new(in_stream, self.password.as_bytes())?;
+        let out_fd = out_stream.as_raw_fd();
+        guard.watch(out_fd)?;
+        let fds = [in_fd, out_fd];
+        // ClientHello read for routing is replayed to the handshake server.
+        let in_stream = PrefixedReadStream::new(in_stream, client_hello);
+        let mut in_stream =
+            HashedWriteStream::new(in_stream, &self.passwords).map_err(anyhow::Error::from)?;
         let mut hmac = in_stream.hmac_handler();
         let (mut out_r, mut out_w) = out_stream.split();
         let (mut in_r, mut in_w) = in_stream.split();
-        let (switch, cp) = FirstRetGroup::new(
-            copy_until_handshake_finished(&mut in_r, &mut out_w, &hmac),
-            Box::pin(copy_until_eof(&mut out_r, &mut in_w)),
+        let v1 = self.opts.protocol_version == Protocol::V1;
+        let handshake = timeout_or_shutdown(
+            Duration::from_secs(self.opts.handshake_timeout),
+            &fds,
+            async {
+                match v1 {
+                    _ if unexpected_sni => Ok((
+                        SwitchResult::DirectProxy(DirectReason::UnexpectedSni),
+                        FutureOrOutput::Future(Box::pin(copy_until_eof(&mut out_r, &mut in_w))),
+                    )),
+                    // Switched without a hmac once both sides finished.
+                    true => ErrGroup::new(
+                        copy_until_finished(&mut in_r, &mut out_w),
+                        copy_until_finished(&mut out_r, &mut in_w),
+                    )
+                    .await
+                    .map(|_| {
+                        (
+                            SwitchResult::Switch(Vec::new(), false),
+                            FutureOrOutput::Output(()),
+                        )
+                    }),
+                    false => {
+                        FirstRetGroup::new(
+                            copy_until_handshake_finished(&mut in_r, &mut out_w, &hmac),
+                            Box::pin(copy_until_eof(&mut out_r, &mut in_w)),
+                        )
+                        .await
+                    }
+                }
+            },
         )
-        .await?;
+        .await
+        .map_err(|_| RelayError::Timeout)
+        .and_then(|handshake| handshake.map_err(|e| RelayError::TlsHandshake(e.into())));
+        let (switch, cp) = handshake.map_err(|e| {
+            Metrics::inc(&self.metrics.handshake_failures);
+            if let Some(sni) = &sni {
+                Metrics::inc(&sni.handshake_failures);
+            }
+            e
+        })?;
+        drop(permit);
+        Metrics::inc(&self.metrics.handshakes);
+        if let Some(sni) = &sni {
+            Metrics::inc(&sni.handshakes);
+        }
+        self.metrics.events.handshake(server_name.as_deref());
         hmac.disable();
-        tracing::debug!("handshake finished, switch: {switch:?}");
+        tracing::debug!(
+            peer = %in_stream_addr,
+            duration_ms = start.elapsed().as_millis() as u64,
+            "handshake finished, switch: {switch:?}"
+        );
 
         match switch {
-            SwitchResult::Switch(data_left) => {
+            SwitchResult::Switch(data_left, tagged) => {
                 drop(cp);
+                if let Some(hello) = sni::server_hello(in_stream.head()) {
+                    tracing::debug!(
+                        peer = %in_stream_addr,
+                        "negotiated version: {:?}, cipher suite: {:?}, alpn: {:?}",
+                        hello.version,
+                        hello.cipher_suite,
+                        hello.alpn
+                    );
+                }
                 let mut in_stream = in_stream.into_inner();
                 let (mut in_r, mut in_w) = in_stream.split();
 
                 // connect our data server
                 let _ = out_stream.shutdown().await;
+                guard.release(out_fd);
                 drop(out_stream);
-                let mut data_stream = TcpStream::connect(&self.data_address).await?;
-                mod_tcp_conn(&mut data_stream, true, self.opts.nodelay);
+                // Payload sent with the hmac, without the PROXY protocol header.
+                let mut payload_len = data_left.len();
+                let (mut data_stream, data_left) = match &self.data_server {
+                    DataServer::Fixed {
+                        address,
+                        proxy_protocol,
+                        backends,
+                    } => {
+                        let (address, data_left) = match tagged {
+                            false => (address.as_ref(), data_left),
+                            true => {
+                                let (tag, len) = backend::decode_tag(&data_left)?;
+                                let address = backend::route(backends, tag).ok_or_else(|| {
+                                    anyhow::anyhow!("unknown backend tag {tag:?}")
+                                })?;
+                                tracing::debug!(peer = %in_stream_addr, tag, "backend {address} chosen");
+                                payload_len -= len;
+                                (address, data_left[len..].to_vec())
+                            }
+                        };
+                        let data_stream = self
+                            .connect_with_retries(address)
+                            .await
+                            .map_err(|e| RelayError::UpstreamConnect(e.into()))?;
+                        match proxy_protocol {
+                            true => {
+                                let peer_addr = match &in_stream_addr {
+                                    PeerAddr::Tcp(addr) => Some(*addr),
+                                    PeerAddr::Unix => None,
+                                };
+                                let mut header = proxy::encode_v2(peer_addr, local_addr);
+                                header.extend_from_slice(&data_left);
+                                (data_stream, header)
+                            }
+                            false => (data_stream, data_left),
+                        }
+                    }
+                    DataServer::Socks5 => {
+                        if tagged {
+                            return Err(anyhow::anyhow!("routing tag sent to socks5 server").into());
+                        }
+                        let (target, len) = Address::decode(&data_left)?;
+                        tracing::debug!(peer = %in_stream_addr, %target, "connect socks5 target");
+                        let connected = async {
+                            let addrs = match &target {
+                                Address::Socket(addr) => vec![*addr],
+                                Address::Domain(host, port) => {
+                                    self.resolver.lookup_host(host, *port).await?
+                                }
+                            };
+                            connect(addrs.as_slice(), &self.opts).await
+                        };
+                        let data_stream = connected
+                            .await
+                            .map_err(|e| RelayError::UpstreamConnect(e.into()))?;
+                        payload_len -= len;
+                        (data_stream, data_left[len..].to_vec())
+                    }
+                };
+                mod_tcp_conn(&mut data_stream, &self.opts, Direction::Outbound);
+                guard.watch(data_stream.as_raw_fd())?;
                 tracing::debug!("data server connected, start relay");
+                let fds = [in_fd, data_stream.as_raw_fd()];
                 let (mut data_r, mut data_w) = data_stream.split();
-                let (result, _) = data_w.write(data_left).await;
+                let (result, _) = data_w.write_all(data_left).await;
                 result?;
-                ErrGroup::new(
-                    copy_with_application_data::<0, _, _>(&mut data_r, &mut in_w, None),
-                    copy_without_application_data(&mut in_r, &mut data_w),
+                let inbound_counter = ByteCounter::new(&self.metrics.bytes_inbound)
+                    .with_sni(sni.as_ref().map(|sni| &sni.bytes_inbound));
+                let outbound_counter = ByteCounter::new(&self.metrics.bytes_outbound)
+                    .with_sni(sni.as_ref().map(|sni| &sni.bytes_outbound));
+                inbound_counter.add(payload_len as u64);
+                let total_limits = self.total_limits.as_deref();
+                let activity = Cell::new(Instant::now());
+                let outbound_limiter = Limiter::new(
+                    self.opts.rate_limit,
+                    total_limits.map(|l| &l.outbound),
+                    &activity,
+                );
+                let inbound_limiter = Limiter::new(
+                    self.opts.rate_limit,
+                    total_limits.map(|l| &l.inbound),
+                    &activity,
+                );
+                let inbound_copy = async {
+                    if v1 {
+                        return copy_plain(
+                            &mut in_r,
+                            &mut data_w,
+                            self.opts.buffer_bytes(),
+                            &inbound_counter,
+                            &inbound_limiter,
+                        )
+                        .await;
+                    }
+                    // Unix sockets are not spliced, there is no tcp stream to open for them.
+                    #[cfg(target_os = "linux")]
+                    if self.opts.splice && matches!(in_stream_addr, PeerAddr::Tcp(_)) {
+                        let mut in_r = dup_tcp_stream(in_fd)?;
+                        return splice_without_application_data(
+                            &mut in_r,
+                            &mut data_w,
+                            &inbound_counter,
+                            &inbound_limiter,
+                        )
+                        .await;
+                    }
+                    copy_without_application_data(
+                        &mut in_r,
+                        &mut data_w,
+                        self.opts.buffer_bytes(),
+                        &inbound_counter,
+                        &inbound_limiter,
+                    )
+                    .await
+                };
+                let outbound_copy = async {
+                    match v1 {
+                        true => {
+                            copy_plain(
+                                &mut data_r,
+                                &mut in_w,
+                                self.opts.buffer_bytes(),
+                                &outbound_counter,
+                                &outbound_limiter,
+                            )
+                            .await
+                        }
+                        false => {
+                            copy_with_application_data::<0, _, _>(
+                                &mut data_r,
+                                &mut in_w,
+                                None,
+                                self.opts.buffer_bytes(),
+                                &outbound_counter,
+                                &outbound_limiter,
+                            )
+                            .await
+                        }
+                    }
+                };
+                let relay = ErrGroup::new(outbound_copy, inbound_copy);
+                let relayed = relay_until_expired(
+                    Duration::from_secs(self.opts.idle_timeout),
+                    self.opts.max_connection_deadline(start),
+                    &activity,
+                    &fds,
+                    relay,
                 )
-                .await?;
+                .await;
+                let summary = Relayed {
+                    inbound: inbound_counter.get(),
+                    outbound: outbound_counter.get(),
+                    duration: start.elapsed(),
+                };
+                match relayed {
+                    Ok(Ok(_)) => Ok(summary),
+                    Ok(Err(e)) => Err(RelayError::Relay(summary, e.into())),
+                    Err(expired) => {
+                        tracing::info!(peer = %in_stream_addr, "Relay closed for {expired}");
+                        Ok(summary)
+                    }
+                }
             }
-            SwitchResult::DirectProxy => match cp {
-                crate::util::FutureOrOutput::Future(cp) => {
-                    ErrGroup::new(cp, copy_until_eof(in_r, out_w)).await?;
+            SwitchResult::DirectProxy(reason) => {
+                match reason {
+                    DirectReason::InvalidTls
+                    | DirectReason::PlainAfterHandshake
+                    | DirectReason::UnexpectedSni => {
+                        Metrics::inc(&self.metrics.handshake_failures);
+                        if let Some(sni) = &sni {
+                            Metrics::inc(&sni.handshake_failures);
+                        }
+                    }
+                    DirectReason::HmacMismatch => Metrics::inc(&self.metrics.bad_password),
                 }
-                crate::util::FutureOrOutput::Output(_) => {
-                    copy_until_eof(in_r, out_w).await?;
+                let copied = match cp {
+                    crate::util::FutureOrOutput::Future(cp) => {
+                        ErrGroup::new(cp, copy_until_eof(in_r, out_w))
+                            .await
+                            .map(|_| ())
+                    }
+                    crate::util::FutureOrOutput::Output(_) => copy_until_eof(in_r, out_w).await,
+                };
+                if let Err(e) = copied {
+                    tracing::debug!(peer = %in_stream_addr, error = %e, "direct relay failed");
                 }
-            },
+                // Relayed like a probe, the failure is reported after it ends.
+                return Err(match reason {
+                    DirectReason::InvalidTls => {
+                        RelayError::TlsHandshake(anyhow::anyhow!("not a valid tls handshake"))
+                    }
+                    DirectReason::PlainAfterHandshake => RelayError::TlsHandshake(anyhow::anyhow!(
+                        "data outside tls records after the handshake, the client may run protocol v1"
+                    )),
+                    DirectReason::UnexpectedSni => RelayError::TlsHandshake(anyhow::anyhow!(
+                        "sni {:?} is not the expected one",
+                        server_name.unwrap_or_default()
+                    )),
+                    DirectReason::HmacMismatch => RelayError::BadPassword,
+                });
+            }
+        }
+    }
+
+    async fn connect(&self, address: &str) -> std::io::Result<monoio::net::TcpStream> {
+        let addrs = self.resolver.resolve(address).await?;
+        connect(addrs.as_slice(), &self.opts).await
+    }
+
+    /// Connect a data server, retrying backend_retries times with backoff doubled after
+    /// each retry, so a restarting data server does not fail the relay. All retries share
+    /// one connect_timeout.
+    async fn connect_with_retries(&self, address: &str) -> std::io::Result<monoio::net::TcpStream> {
+        let deadline = Instant::now() + Duration::from_secs(self.opts.connect_timeout);
+        let addrs = self.resolver.resolve(address).await?;
+        let mut backoff = Duration::from_millis(self.opts.retry_backoff);
+        let mut retries = 0;
+        loop {
+            let err = match connect_until(addrs.as_slice(), &self.opts, deadline.into()).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            };
+            if retries == self.opts.backend_retries || Instant::now() + backoff >= deadline {
+                return Err(err);
+            }
+            retries += 1;
+            tracing::warn!(
+                "Connect {address:?} failed: {err}, retry {retries}/{} in {backoff:?}",
+                self.opts.backend_retries
+            );
+            monoio::time::sleep(backoff).await;
+            backoff *= 2;
         }
-        Ok(())
     }
 }
 
 enum SwitchResult {
-    Switch(Vec<u8>),
-    DirectProxy,
+    /// Data following the hmac, and whether the hmac marks a tagged frame.
+    Switch(Vec<u8>, bool),
+    DirectProxy(DirectReason),
+}
+
+/// Why the connection is relayed to the handshake server directly.
+#[derive(Debug)]
+enum DirectReason {
+    InvalidTls,
+    /// Not tls after the client finished its handshake, like a protocol v1 client.
+    PlainAfterHandshake,
+    /// The ClientHello sni is not --expected-sni.
+    UnexpectedSni,
+    HmacMismatch,
 }
 
 impl std::fmt::Debug for SwitchResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Switch(_) => write!(f, "Switch"),
-            Self::DirectProxy => write!(f, "DirectProxy"),
+            Self::Switch(_, tagged) => write!(f, "Switch(tagged: {tagged})"),
+            Self::DirectProxy(reason) => write!(f, "DirectProxy({reason:?})"),
+        }
+    }
+}
+
+/// Relay the tls records of one direction of a protocol v1 handshake until its Finished,
+/// the record after ChangeCipherSpec. Records are read exactly, so nothing sent after
+/// the handshake reaches the handshake server. Only tls 1.2 works, the ChangeCipherSpec
+/// of tls 1.3 does not precede Finished.
+async fn copy_until_finished<R, W>(read_half: &mut R, write_half: &mut W) -> std::io::Result<()>
+where
+    R: AsyncReadRent,
+    W: AsyncWriteRent,
+{
+    const CHANGE_CIPHER_SPEC: u8 = 0x14;
+    const ALERT: u8 = 0x15;
+    const HANDSHAKE: u8 = 0x16;
+    let mut has_seen_change_cipher_spec = false;
+    let mut header = vec![0; 5];
+    loop {
+        let (res, header_) = read_half.read_exact(header).await;
+        header = header_;
+        res?;
+        if !matches!(header[0], CHANGE_CIPHER_SPEC | ALERT | HANDSHAKE) || header[1] != 0x03 {
+            let _ = write_half.shutdown().await;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("not a tls 1.2 handshake: header {:?}", &header[..3]),
+            ));
+        }
+        let finished = has_seen_change_cipher_spec;
+        has_seen_change_cipher_spec |= header[0] == CHANGE_CIPHER_SPEC;
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let (res, header_) = write_half.write_all(header).await;
+        header = header_;
+        res?;
+        let (res, record) = read_half.read_exact(vec![0; len]).await;
+        res?;
+        let (res, _) = write_half.write_all(record).await;
+        res?;
+        if finished {
+            return Ok(());
         }
     }
 }
@@ -188,6 +653,17 @@ where
             if header_buf[0] == HANDSHAKE {
                 has_seen_handshake = true;
             }
+            // The rest of an invalid record is relayed with what follows by the direct proxy,
+            // it may never be complete.
+            if !valid {
+                tracing::debug!("early invalid tls: header {:?}", &header_buf[..3]);
+                return Ok(SwitchResult::DirectProxy(
+                    match has_seen_change_cipher_spec {
+                        true => DirectReason::PlainAfterHandshake,
+                        false => DirectReason::InvalidTls,
+                    },
+                ));
+            }
             // Copy data.
             let mut to_copy = data_size;
             while to_copy != 0 {
@@ -208,10 +684,6 @@ where
                 data_buf = buf.into_inner();
             }
             tracing::debug!("copied data with length {:?}", data_size);
-            if !valid {
-                tracing::debug!("early invalid tls: header {:?}", &header_buf[..3]);
-                return Ok(SwitchResult::DirectProxy);
-            }
             continue;
         }
 
@@ -243,15 +715,25 @@ where
         }
 
         // Now hmac has been read and copied.
-        // If hmac matches, we need to read current data and return.
-        let hash = hmac.hash();
-        tracing::debug!("hmac calculated: {hash:?}");
-        if data_hmac_buf[0..HMAC_SIZE] == hash[0..HMAC_SIZE] {
-            tracing::debug!("hmac matches");
+        // If hmac of any password matches, we need to read current data and return.
+        let hashes = hmac.hashes();
+        tracing::debug!("hmac calculated: {hashes:?}");
+        // Tagged clients send the second 8 bytes of the hmac.
+        let matched =
+            hashes
+                .iter()
+                .enumerate()
+                .find_map(|(idx, hash)| match &data_hmac_buf[0..HMAC_SIZE] {
+                    h if h == &hash[0..HMAC_SIZE] => Some((idx, false)),
+                    h if h == &hash[HMAC_SIZE..2 * HMAC_SIZE] => Some((idx, true)),
+                    _ => None,
+                });
+        if let Some((idx, tagged)) = matched {
+            tracing::debug!("hmac matches password #{idx}, tagged: {tagged}");
             let pure_data = vec![0; data_size - HMAC_SIZE];
             let (read_res, pure_data) = read_half.read_exact(pure_data).await;
             read_res?;
-            return Ok(SwitchResult::Switch(pure_data));
+            return Ok(SwitchResult::Switch(pure_data, tagged));
         }
 
         // Now hmac does not match. We have to acc the counter and do copy.
@@ -277,7 +759,166 @@ where
 
         if application_data_count > 3 {
             tracing::debug!("hmac not matches after 3 times, fallback to direct");
-            return Ok(SwitchResult::DirectProxy);
+            return Ok(SwitchResult::DirectProxy(DirectReason::HmacMismatch));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use monoio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_copy_until_finished() {
+        // ClientKeyExchange, ChangeCipherSpec and the encrypted Finished.
+        let mut flight = vec![0x16, 0x03, 0x03, 0x00, 0x02, 1, 2];
+        flight.extend_from_slice(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        flight.extend_from_slice(&[0x16, 0x03, 0x03, 0x00, 0x03, 7, 8, 9]);
+
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (mut from, _) = listener.accept().await.unwrap();
+            let mut to = TcpStream::connect(addr).await.unwrap();
+            let (mut site, _) = listener.accept().await.unwrap();
+
+            let mut sent = flight.clone();
+            sent.extend_from_slice(b"plain");
+            let (res, _) = client.write_all(sent).await;
+            res.unwrap();
+            copy_until_finished(&mut from, &mut to).await.unwrap();
+            let (res, relayed) = site.read_exact(vec![0; flight.len()]).await;
+            res.unwrap();
+            assert_eq!(relayed, flight);
+            let (res, left) = from.read_exact(vec![0; 5]).await;
+            res.unwrap();
+            assert_eq!(left, b"plain");
+
+            let (res, _) = client.write_all(b"GET / HTTP/1.1\r\n".to_vec()).await;
+            res.unwrap();
+            assert!(copy_until_finished(&mut from, &mut to).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_bad_password_relayed_to_handshake_server() {
+        // A prober finishing a fake handshake and sending frames with wrong hmacs.
+        let mut probe = vec![0x16, 0x03, 0x01, 0x00, 0x04, 1, 2, 3, 4];
+        probe.extend_from_slice(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        for _ in 0..4 {
+            probe.extend_from_slice(&[APPLICATION_DATA, 0x03, 0x03, 0x00, 0x10]);
+            probe.extend_from_slice(&[0xaa; 16]);
         }
+        let response = vec![0x16, 0x03, 0x03, 0x00, 0x02, 0x02, 0x00, 0x15, 0x03, 0x03];
+
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let site = TcpListener::bind("127.0.0.1:0").unwrap();
+            let site_addr = site.local_addr().unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let listen_addr = listener.local_addr().unwrap();
+            let metrics = Arc::new(Metrics::default());
+            let server = ShadowTlsServer::new(
+                site_addr.to_string(),
+                DataServer::Fixed {
+                    address: "127.0.0.1:1".to_string(),
+                    proxy_protocol: false,
+                    backends: Vec::new(),
+                },
+                vec!["password".to_string()],
+                Vec::new(),
+                Opts::default(),
+                metrics.clone(),
+                None,
+            );
+            let relay = monoio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                server.relay(stream, PeerAddr::Tcp(addr)).await
+            });
+            let probe_len = probe.len();
+            let site_response = response.clone();
+            let site = monoio::spawn(async move {
+                let (mut stream, _) = site.accept().await.unwrap();
+                let (res, received) = stream.read_exact(vec![0; probe_len]).await;
+                res.unwrap();
+                let (res, _) = stream.write_all(site_response).await;
+                res.unwrap();
+                stream.shutdown().await.unwrap();
+                received
+            });
+
+            let mut prober = TcpStream::connect(listen_addr).await.unwrap();
+            let (res, _) = prober.write_all(probe.clone()).await;
+            res.unwrap();
+            prober.shutdown().await.unwrap();
+            let (res, received) = prober.read_exact(vec![0; response.len()]).await;
+            res.unwrap();
+            assert_eq!(received, response);
+            assert_eq!(site.await, probe);
+            assert!(matches!(relay.await, Err(RelayError::BadPassword)));
+            assert_eq!(metrics.bad_password.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_dropped_relay_closes_sockets() {
+        // io_uring leaves the pending reads of a dropped relay around, if available.
+        let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let site = TcpListener::bind("127.0.0.1:0").unwrap();
+            let site_addr = site.local_addr().unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let listen_addr = listener.local_addr().unwrap();
+            let server = ShadowTlsServer::new(
+                site_addr.to_string(),
+                DataServer::Fixed {
+                    address: "127.0.0.1:1".to_string(),
+                    proxy_protocol: false,
+                    backends: Vec::new(),
+                },
+                vec!["password".to_string()],
+                Vec::new(),
+                Opts::default(),
+                Arc::new(Metrics::default()),
+                None,
+            );
+            let relay = monoio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let relay = server.relay(stream, PeerAddr::Tcp(addr));
+                monoio::time::timeout(Duration::from_millis(100), relay).await
+            });
+            let site = monoio::spawn(async move {
+                let (mut stream, _) = site.accept().await.unwrap();
+                let (res, _) = stream.read(vec![0; 16]).await;
+                res.unwrap();
+                let (res, _) = stream.read(vec![0; 16]).await;
+                res.unwrap()
+            });
+
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            // Stuck in the middle of a record header.
+            let (res, _) = client.write_all(vec![0x16, 0x03, 0x01]).await;
+            res.unwrap();
+            assert!(relay.await.is_err());
+            let closed = monoio::time::timeout(Duration::from_secs(1), async {
+                let (res, _) = client.read(vec![0; 16]).await;
+                (res.unwrap_or(0), site.await)
+            });
+            assert_eq!(closed.await.unwrap(), (0, 0));
+        });
     }
 }
@@ -0,0 +1,100 @@
+//! Signal handling for graceful shutdown.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use monoio::{io::AsyncReadRent, net::UnixStream};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    SigId,
+};
+
+const SIGNALS: [i32; 2] = [SIGINT, SIGTERM];
+
+// Only used when the wakeup socket can not be registered.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ShutdownSignal is set once SIGINT or SIGTERM is received.
+#[derive(Clone)]
+pub struct ShutdownSignal(Option<Arc<AtomicBool>>);
+
+impl ShutdownSignal {
+    /// Install handlers for SIGINT and SIGTERM.
+    /// A second signal terminates the process immediately.
+    pub fn install() -> io::Result<Self> {
+        let flag = Arc::new(AtomicBool::new(false));
+        for sig in SIGNALS {
+            // The conditional one must be registered first, or the first signal
+            // would set the flag and exit at once.
+            signal_hook::flag::register_conditional_shutdown(sig, 1, flag.clone())?;
+            signal_hook::flag::register(sig, flag.clone())?;
+        }
+        Ok(Self(Some(flag)))
+    }
+
+    /// Signal that is never triggered, without installing handlers, for tests.
+    pub fn never() -> Self {
+        Self(None)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0
+            .as_ref()
+            .map_or(false, |flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Wait until shutdown is triggered.
+    /// Monoio runtimes can not be woken from the signal thread directly, so the handler
+    /// writes to a socket pair the waiter reads. A periodic timer instead would keep
+    /// waking every worker and change how its io is scheduled.
+    pub async fn wait(&self) {
+        if self.0.is_none() {
+            return std::future::pending().await;
+        }
+        let (mut wakeup, ids) = match Self::register_wakeup() {
+            Ok(registered) => registered,
+            Err(e) => {
+                tracing::warn!("Register shutdown wakeup failed, polling instead: {e}");
+                while !self.is_triggered() {
+                    monoio::time::sleep(POLL_INTERVAL).await;
+                }
+                return;
+            }
+        };
+        // Flag handlers are registered first, so the flag is set once woken.
+        while !self.is_triggered() {
+            let (res, _) = wakeup.read(vec![0; 16]).await;
+            if res.is_err() {
+                monoio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+        for id in ids {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+
+    fn register_wakeup() -> io::Result<(UnixStream, Vec<SigId>)> {
+        let (rx, tx) = std::os::unix::net::UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        let txs = [tx.try_clone()?, tx];
+        let mut ids = Vec::with_capacity(SIGNALS.len());
+        for (sig, tx) in SIGNALS.into_iter().zip(txs) {
+            match signal_hook::low_level::pipe::register(sig, tx) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    for id in ids {
+                        signal_hook::low_level::unregister(id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok((UnixStream::from_std(rx)?, ids))
+    }
+}
@@ -1,80 +1,185 @@
 use anyhow::Context;
-use tracing::error;
+use shadow_tls::{client::TlsVersion, verify::CertChangeAction, Threads};
 
 use super::Args;
-use std::{collections::HashMap, env, process::exit};
+use std::{env, net::Ipv6Addr, str::FromStr};
 
 macro_rules! env {
     ($key: expr) => {
         match env::var($key).ok() {
-            None => return None,
-            Some(val) if val.is_empty() => return None,
+            None => return Ok(None),
+            Some(val) if val.is_empty() => return Ok(None),
             Some(val) => val,
         }
     };
-    ($key: expr, $fail_fn: expr) => {
+    ($key: expr, $fail_msg: expr) => {
         match env::var($key).ok() {
-            None => return None,
-            Some(val) if val.is_empty() => {
-                $fail_fn();
-                return None;
-            }
+            None => return Ok(None),
+            Some(val) if val.is_empty() => anyhow::bail!($fail_msg),
             Some(val) => val,
         }
     };
 }
 
+/// Plugin environment set by the Shadowsocks process.
+struct Sip003Env {
+    remote_host: String,
+    remote_port: String,
+    local_host: String,
+    local_port: String,
+    plugin_options: String,
+}
+
 // SIP003 [https://shadowsocks.org/en/wiki/Plugin.html](https://shadowsocks.org/en/wiki/Plugin.html)
-pub(crate) fn get_sip003_arg() -> Option<Args> {
-    let ss_remote_host = env!("SS_REMOTE_HOST");
-    let ss_remote_port = env!("SS_REMOTE_PORT");
-    let ss_local_host = env!("SS_LOCAL_HOST");
-    let ss_local_port = env!("SS_LOCAL_PORT");
-    let ss_plugin_options = env!("SS_PLUGIN_OPTIONS", || {
-        error!("need SS_PLUGIN_OPTIONS when as SIP003 plugin");
-        exit(-1);
-    });
-
-    let opts = parse_sip003_options(&ss_plugin_options).unwrap();
-    let opts: HashMap<_, _> = opts.into_iter().collect();
-
-    let threads = opts.get("threads").map(|s| s.parse::<u8>().unwrap());
+/// Args from SIP003 environment variables, None if not running as a SIP003 plugin.
+/// Plugins only carry tcp, Shadowsocks relays udp by itself.
+pub(crate) fn get_sip003_arg() -> anyhow::Result<Option<Args>> {
+    let env = Sip003Env {
+        remote_host: env!("SS_REMOTE_HOST"),
+        remote_port: env!("SS_REMOTE_PORT"),
+        local_host: env!("SS_LOCAL_HOST"),
+        local_port: env!("SS_LOCAL_PORT"),
+        plugin_options: env!(
+            "SS_PLUGIN_OPTIONS",
+            "need SS_PLUGIN_OPTIONS when as SIP003 plugin"
+        ),
+    };
+    build_args(&env).map(Some)
+}
+
+/// host:port addresses of hosts separated by `|` like shadowsocks-libev passes several
+/// server hosts, IPv6 hosts are bracketed.
+fn addresses(hosts: &str, port: &str, name: &str) -> anyhow::Result<Vec<String>> {
+    port.parse::<u16>()
+        .with_context(|| format!("invalid {name} {port:?}"))?;
+    Ok(hosts
+        .split('|')
+        .map(|host| match host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{host}]:{port}"),
+            Err(_) => format!("{host}:{port}"),
+        })
+        .collect())
+}
+
+fn build_args(env: &Sip003Env) -> anyhow::Result<Args> {
+    let ss_plugin_options = &env.plugin_options;
+    let remote_addrs = addresses(&env.remote_host, &env.remote_port, "SS_REMOTE_PORT")?;
+    // The local side is a single host.
+    let local_addr = addresses(&env.local_host, &env.local_port, "SS_LOCAL_PORT")?.swap_remove(0);
+    let opts: PluginOptions = ss_plugin_options
+        .parse()
+        .with_context(|| format!("invalid SS_PLUGIN_OPTIONS {ss_plugin_options:?}"))?;
     let passwd = opts
-        .get("passwd")
-        .expect("need passwd param(like passwd=123456)");
+        .password
+        .context("need passwd param(like passwd=123456)")?;
 
-    let args_opts = crate::Opts {
-        threads,
+    let args_opts = shadow_tls::Opts {
+        threads: opts.threads,
+        nodelay: opts.nodelay,
+        fast_open: opts.fast_open,
         ..Default::default()
     };
-    let args = if opts.get("server").is_some() {
-        let tls_addr = opts
-            .get("tls")
-            .expect("need tls param(like tls=xxx.com:443)");
+    let args = if opts.server {
+        let tls_addr = opts.tls.context("need tls param(like tls=xxx.com:443)")?;
         Args {
-            cmd: crate::Commands::Server {
-                listen: format!("{ss_remote_host}:{ss_remote_port}"),
-                server_addr: format!("{ss_local_host}:{ss_local_port}"),
-                tls_addr: tls_addr.to_owned(),
-                password: passwd.to_owned(),
-            },
+            cmd: crate::Commands::Server(crate::ServerArgs {
+                listen: remote_addrs,
+                server_addr: Some(local_addr),
+                tls_addr,
+                passwords: vec![passwd],
+                password_file: None,
+                sni_map: Vec::new(),
+                backend_map: Vec::new(),
+                allow: Vec::new(),
+                deny: Vec::new(),
+                send_proxy_protocol: false,
+                accept_proxy_protocol: false,
+                socks5: false,
+            }),
             opts: args_opts,
         }
     } else {
         let host = opts
-            .get("host")
-            .expect("need host param(like host=www.baidu.com)");
+            .host
+            .context("need host param(like host=www.baidu.com)")?;
         Args {
-            cmd: crate::Commands::Client {
-                listen: format!("{ss_local_host}:{ss_local_port}"),
-                server_addr: format!("{ss_remote_host}:{ss_remote_port}"),
-                tls_name: host.to_owned(),
-                password: passwd.to_owned(),
-            },
+            cmd: crate::Commands::Client(crate::ClientArgs {
+                listen: vec![local_addr],
+                server_addrs: remote_addrs,
+                lb_policy: shadow_tls::upstream::LbPolicy::RoundRobin,
+                health_interval: 0,
+                health_timeout: 5,
+                tls_names: host.split(',').map(ToOwned::to_owned).collect(),
+                password: Some(passwd),
+                password_file: None,
+                alpn: opts.alpn,
+                pin_sha256: Vec::new(),
+                insecure: false,
+                verify_name: None,
+                cert_change_action: CertChangeAction::Log,
+                tls_min_version: TlsVersion::V1_2,
+                tls_max_version: TlsVersion::V1_3,
+                cipher_suites: Vec::new(),
+                accept_proxy_protocol: false,
+                socks5: false,
+                backend_tag: None,
+            }),
             opts: args_opts,
         }
     };
-    Some(args)
+    Ok(args)
+}
+
+/// Settings recognized in SS_PLUGIN_OPTIONS, later keys override earlier ones.
+#[derive(Debug, Default, PartialEq)]
+struct PluginOptions {
+    /// Run the server side instead of the client.
+    server: bool,
+    /// `passwd` or `password`.
+    password: Option<String>,
+    /// Handshake server of the server side.
+    tls: Option<String>,
+    /// `host` or `sni`, comma separated names of the client side.
+    host: Option<String>,
+    threads: Option<Threads>,
+    nodelay: bool,
+    /// `fast-open`, appended by shadowsocks-libev when its fast open is on.
+    fast_open: bool,
+    /// Comma separated alpn protocols of the client side.
+    alpn: Vec<String>,
+}
+
+impl FromStr for PluginOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let flag = |key: &str, value: &str| match value {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => anyhow::bail!("invalid {key} param {value:?}, expect true or false"),
+        };
+        let mut opts = Self::default();
+        for (key, value) in parse_sip003_options(s)? {
+            match key.as_str() {
+                "server" => opts.server = flag(&key, &value)?,
+                "passwd" | "password" => opts.password = Some(value),
+                "tls" => opts.tls = Some(value),
+                "host" | "sni" => opts.host = Some(value),
+                "threads" => {
+                    opts.threads = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("invalid threads param {value:?}"))?,
+                    )
+                }
+                "nodelay" => opts.nodelay = flag(&key, &value)?,
+                "fast-open" => opts.fast_open = flag(&key, &value)?,
+                "alpn" => opts.alpn = value.split(',').map(ToOwned::to_owned).collect(),
+                _ => anyhow::bail!("unknown option {key:?}"),
+            }
+        }
+        Ok(opts)
+    }
 }
 
 // Parse SIP003 optinos from env
@@ -100,6 +205,9 @@ fn parse_sip003_options(s: &str) -> Result<Vec<(String, String)>, anyhow::Error>
         // read value
         let (offset, value) = index_unescaped(&s[i..], &[b'=', b';']).context("read value")?;
         i += offset;
+        if i < s.len() && s.as_bytes()[i] == b'=' {
+            return Err(anyhow::format_err!("unexpected '=' in {}", &s[i..]));
+        }
         opts.push((key, value));
         // Skip the semicolon.
         i += 1;
@@ -129,7 +237,7 @@ fn index_unescaped(s: &str, term: &[u8]) -> Result<(usize, String), anyhow::Erro
         unesc.push(b);
         i += 1;
     }
-    Ok((i, String::from_utf8(unesc).unwrap()))
+    Ok((i, String::from_utf8(unesc)?))
 }
 
 #[cfg(test)]
@@ -147,3 +255,72 @@ fn test_parse_sip003_options() {
         ]
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_sip003_args() {
+    for malformed in ["a=b=c", ";a=b", "a=b;;c", "a=b\\", "=b"] {
+        assert!(parse_sip003_options(malformed).is_err(), "{malformed}");
+    }
+    assert_eq!(
+        parse_sip003_options("a=\\;\\=;b").unwrap(),
+        vec![
+            ("a".to_string(), ";=".to_string()),
+            ("b".to_string(), "1".to_string())
+        ]
+    );
+
+    let env = |remote_host: &str, remote_port: &str, plugin_options: &str| Sip003Env {
+        remote_host: remote_host.to_string(),
+        remote_port: remote_port.to_string(),
+        local_host: "127.0.0.1".to_string(),
+        local_port: "8388".to_string(),
+        plugin_options: plugin_options.to_string(),
+    };
+    let args = build_args(&env("::|0.0.0.0", "443", "server;tls=a.com:443;passwd=p")).unwrap();
+    match args.cmd {
+        crate::Commands::Server(server) => {
+            assert_eq!(server.listen, vec!["[::]:443", "0.0.0.0:443"]);
+            assert_eq!(server.server_addr.as_deref(), Some("127.0.0.1:8388"));
+        }
+        _ => panic!("expect server args"),
+    }
+    let args = build_args(&env("2001:db8::1", "443", "host=a.com;passwd=p")).unwrap();
+    match args.cmd {
+        crate::Commands::Client(client) => {
+            assert_eq!(client.listen, vec!["127.0.0.1:8388"]);
+            assert_eq!(client.server_addrs, vec!["[2001:db8::1]:443"]);
+        }
+        _ => panic!("expect client args"),
+    }
+    assert!(build_args(&env("1.2.3.4", "https", "host=a.com;passwd=p")).is_err());
+    assert!(build_args(&env("1.2.3.4", "443", "host=a.com")).is_err());
+    assert!(build_args(&env("1.2.3.4", "443", "server;passwd=p")).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_plugin_options() {
+    assert_eq!(
+        "server;password=a\\;b;tls=a.com:443;threads=2;nodelay;fast-open=false"
+            .parse::<PluginOptions>()
+            .unwrap(),
+        PluginOptions {
+            server: true,
+            password: Some("a;b".to_string()),
+            tls: Some("a.com:443".to_string()),
+            threads: Some(Threads::Fixed(2)),
+            nodelay: true,
+            ..Default::default()
+        }
+    );
+    let opts: PluginOptions = "sni=a.com,b.com;passwd=1;passwd=2;alpn=h2,http/1.1"
+        .parse()
+        .unwrap();
+    assert_eq!(opts.host.as_deref(), Some("a.com,b.com"));
+    assert_eq!(opts.password.as_deref(), Some("2"));
+    assert_eq!(opts.alpn, vec!["h2", "http/1.1"]);
+    for invalid in ["pasword=1", "server=yes", "threads=0", "host=a=b"] {
+        assert!(invalid.parse::<PluginOptions>().is_err(), "{invalid}");
+    }
+}
@@ -0,0 +1,298 @@
+//! Handshake server selection by the SNI in ClientHello, and ServerHello inspection.
+
+use std::{fmt::Display, io, str::FromStr};
+
+use monoio::io::{AsyncReadRent, AsyncReadRentExt};
+
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_HELLO: u8 = 0x02;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// SniRoute maps server names matching pattern to a handshake server, written as
+/// `pattern=address`. Pattern `*.example.com` matches any subdomain of example.com.
+#[derive(Debug, Clone)]
+pub struct SniRoute {
+    pattern: String,
+    address: String,
+}
+
+impl SniRoute {
+    fn matches(&self, name: &str) -> bool {
+        match self.pattern.strip_prefix('*') {
+            Some(suffix) => {
+                let name = name.as_bytes();
+                name.len() > suffix.len()
+                    && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+            }
+            None => name.eq_ignore_ascii_case(&self.pattern),
+        }
+    }
+}
+
+impl FromStr for SniRoute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, address) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expect pattern=address, got {s}"))?;
+        if pattern.is_empty() || address.is_empty() {
+            anyhow::bail!("expect pattern=address, got {s}");
+        }
+        let wildcard_valid = match pattern.strip_prefix("*.") {
+            Some(suffix) => !suffix.contains('*'),
+            None => !pattern.contains('*'),
+        };
+        if !wildcard_valid {
+            anyhow::bail!("wildcard is only supported as the first label, got {pattern}");
+        }
+        crate::util::validate_address("handshake server", address)?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            address: address.to_string(),
+        })
+    }
+}
+
+impl Display for SniRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.pattern, self.address)
+    }
+}
+
+/// Address of the first route matching name.
+pub fn route<'a>(routes: &'a [SniRoute], name: &str) -> Option<&'a str> {
+    routes
+        .iter()
+        .find(|r| r.matches(name))
+        .map(|r| r.address.as_str())
+}
+
+/// Read the first record, which is ClientHello for a tls client.
+/// Only the header is read if the record is not a handshake, the caller relays whatever
+/// is read to the handshake server anyway.
+/// A handshake record longer than max_size fails before its body is buffered.
+pub async fn read_client_hello<S: AsyncReadRent>(
+    stream: &mut S,
+    max_size: usize,
+) -> io::Result<Vec<u8>> {
+    let (res, header) = stream.read_exact(vec![0; 5]).await;
+    res?;
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if header[0] != HANDSHAKE {
+        return Ok(header);
+    }
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("ClientHello of {len} bytes exceeds the max {max_size}"),
+        ));
+    }
+    let (res, body) = stream.read_exact(vec![0; len]).await;
+    res?;
+    let mut record = header;
+    record.extend_from_slice(&body);
+    Ok(record)
+}
+
+/// Server name in the ClientHello record, if any.
+pub fn server_name(record: &[u8]) -> Option<&str> {
+    let mut r = Reader(record);
+    if r.u8()? != HANDSHAKE {
+        return None;
+    }
+    r.skip(4)?;
+    if r.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // Length, version and random.
+    r.skip(3 + 2 + 32)?;
+    let session_id = r.u8()? as usize;
+    r.skip(session_id)?;
+    let cipher_suites = r.u16()? as usize;
+    r.skip(cipher_suites)?;
+    let compression_methods = r.u8()? as usize;
+    r.skip(compression_methods)?;
+    let extensions = r.u16()? as usize;
+    let mut r = Reader(r.take(extensions)?);
+    while !r.0.is_empty() {
+        let ext_type = r.u16()?;
+        let ext_len = r.u16()? as usize;
+        let mut ext = Reader(r.take(ext_len)?);
+        if ext_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let list_len = ext.u16()? as usize;
+        let mut list = Reader(ext.take(list_len)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok();
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Parameters chosen by the handshake server.
+pub struct ServerHello {
+    pub version: rustls::ProtocolVersion,
+    pub cipher_suite: rustls::CipherSuite,
+    /// Only visible with tls 1.2, tls 1.3 sends it in the encrypted extensions.
+    pub alpn: Option<String>,
+}
+
+/// Parameters in the ServerHello record, only the beginning of the record is required.
+pub fn server_hello(record: &[u8]) -> Option<ServerHello> {
+    let mut r = Reader(record);
+    if r.u8()? != HANDSHAKE {
+        return None;
+    }
+    r.skip(4)?;
+    if r.u8()? != SERVER_HELLO {
+        return None;
+    }
+    r.skip(3)?;
+    let version = r.u16()?;
+    r.skip(32)?;
+    let session_id = r.u8()? as usize;
+    r.skip(session_id)?;
+    let mut hello = ServerHello {
+        version: version.into(),
+        cipher_suite: r.u16()?.into(),
+        alpn: None,
+    };
+    r.skip(1)?;
+    // Extensions are optional before tls 1.3.
+    let extensions = r.u16().unwrap_or(0) as usize;
+    let mut r = Reader(r.take(extensions)?);
+    while !r.0.is_empty() {
+        let ext_type = r.u16()?;
+        let ext_len = r.u16()? as usize;
+        let mut ext = Reader(r.take(ext_len)?);
+        match ext_type {
+            EXTENSION_SUPPORTED_VERSIONS => hello.version = ext.u16()?.into(),
+            EXTENSION_ALPN => {
+                ext.skip(2)?;
+                let len = ext.u8()? as usize;
+                hello.alpn = Some(String::from_utf8_lossy(ext.take(len)?).into_owned());
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_server_name() {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let name = "www.example.com".try_into().unwrap();
+        let mut conn = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut record = Vec::new();
+        conn.write_tls(&mut record).unwrap();
+        assert_eq!(server_name(&record), Some("www.example.com"));
+        assert_eq!(server_name(&record[..record.len() - 1]), None);
+
+        let routes: Vec<SniRoute> = ["*.example.com=a:443", "example.com=b:443"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        assert_eq!(route(&routes, "WWW.example.com"), Some("a:443"));
+        assert_eq!(route(&routes, "example.com"), Some("b:443"));
+        assert_eq!(route(&routes, "badexample.com"), None);
+        assert!("a.*.com=c:443".parse::<SniRoute>().is_err());
+        assert!("example.com=example.com".parse::<SniRoute>().is_err());
+    }
+
+    #[test]
+    fn test_server_hello() {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        // Session id, cipher suite and compression method.
+        body.extend_from_slice(&[1, 0xaa, 0x13, 0x01, 0]);
+        let extensions = [0x00, 0x2b, 0, 2, 0x03, 0x04];
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut record = vec![HANDSHAKE, 0x03, 0x03, 0, body.len() as u8 + 4];
+        record.extend_from_slice(&[SERVER_HELLO, 0, 0, body.len() as u8]);
+        record.extend_from_slice(&body);
+        // Followed by other records.
+        record.extend_from_slice(&[0x14, 0x03, 0x03, 0, 1, 1]);
+        let hello = server_hello(&record).unwrap();
+        assert_eq!(hello.version, rustls::ProtocolVersion::TLSv1_3);
+        assert_eq!(
+            hello.cipher_suite,
+            rustls::CipherSuite::TLS13_AES_128_GCM_SHA256
+        );
+        assert!(hello.alpn.is_none());
+        assert!(server_hello(&record[..40]).is_none());
+    }
+
+    #[test]
+    fn test_read_client_hello() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut input = vec![HANDSHAKE, 0x03, 0x01, 0x00, 0x04, 1, 2, 3, 4, 0x17];
+            let mut stream = input.as_slice();
+            let record = read_client_hello(&mut stream, 16).await.unwrap();
+            assert_eq!(record, &input[..9]);
+            assert_eq!(stream, &[0x17]);
+
+            // Failed without reading the body.
+            input[3..5].copy_from_slice(&0x8000_u16.to_be_bytes());
+            input.resize(5 + 0x8000, 0);
+            let mut stream = input.as_slice();
+            let e = read_client_hello(&mut stream, 16384).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(stream.len(), 0x8000);
+
+            // Not a handshake, relayed as is.
+            let mut stream = &[0x17, 0x03, 0x03, 0xff, 0xff][..];
+            let record = read_client_hello(&mut stream, 16).await.unwrap();
+            assert_eq!(record, &[0x17, 0x03, 0x03, 0xff, 0xff]);
+        });
+    }
+}
@@ -0,0 +1,320 @@
+//! Minimal SOCKS5 server side handshake(no auth, CONNECT only), client side handshake
+//! for upstream proxies, and the address codec used to carry the requested destination
+//! to shadow-tls server.
+
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use monoio::io::{AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASSWORD: u8 = 0x02;
+/// Version of the username/password subnegotiation, see RFC 1929.
+const AUTH_VERSION: u8 = 0x01;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Destination address in SOCKS5 format: ATYP, address and big endian port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Socket(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let port = match self {
+            Self::Socket(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Self::Socket(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Self::Domain(host, port) => {
+                buf.push(ATYP_DOMAIN);
+                buf.push(host.len() as u8);
+                buf.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        buf.extend_from_slice(&port.to_be_bytes());
+    }
+
+    /// Decode an address from the front of buf, returns it with the bytes consumed.
+    pub fn decode(buf: &[u8]) -> io::Result<(Self, usize)> {
+        let truncated = || invalid_data("truncated socks5 address");
+        let (ip, offset) = match *buf.first().ok_or_else(truncated)? {
+            ATYP_IPV4 => {
+                let octets: [u8; 4] = buf.get(1..5).ok_or_else(truncated)?.try_into().unwrap();
+                (Some(IpAddr::V4(Ipv4Addr::from(octets))), 5)
+            }
+            ATYP_IPV6 => {
+                let octets: [u8; 16] = buf.get(1..17).ok_or_else(truncated)?.try_into().unwrap();
+                (Some(IpAddr::V6(Ipv6Addr::from(octets))), 17)
+            }
+            ATYP_DOMAIN => (None, 2 + *buf.get(1).ok_or_else(truncated)? as usize),
+            _ => return Err(invalid_data("unsupported socks5 address type")),
+        };
+        let port = buf.get(offset..offset + 2).ok_or_else(truncated)?;
+        let port = u16::from_be_bytes([port[0], port[1]]);
+        let address = match ip {
+            Some(ip) => Self::Socket(SocketAddr::new(ip, port)),
+            None => {
+                let host = std::str::from_utf8(&buf[2..offset])
+                    .map_err(|_| invalid_data("socks5 domain is not utf8"))?;
+                Self::Domain(host.to_string(), port)
+            }
+        };
+        Ok((address, offset + 2))
+    }
+}
+
+impl Address {
+    /// Parse host:port, taking ips as socket addresses.
+    pub fn parse(addr: &str) -> io::Result<Self> {
+        if let Ok(addr) = addr.parse() {
+            return Ok(Self::Socket(addr));
+        }
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && host.len() <= u8::MAX as usize => {
+                let port = port
+                    .parse()
+                    .map_err(|_| invalid_input("invalid port of socks5 address"))?;
+                Ok(Self::Domain(host.to_string(), port))
+            }
+            _ => Err(invalid_input("invalid socks5 address")),
+        }
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Socket(addr) => write!(f, "{addr}"),
+            Self::Domain(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+/// Do SOCKS5 handshake with local application and return the requested destination.
+/// Success is replied before the destination is connected, like ss-local does.
+pub async fn accept<S: AsyncReadRent + AsyncWriteRent>(stream: &mut S) -> io::Result<Address> {
+    let (res, head) = stream.read_exact(vec![0; 2]).await;
+    res?;
+    if head[0] != VERSION {
+        return Err(invalid_data("unsupported socks version"));
+    }
+    let (res, methods) = stream.read_exact(vec![0; head[1] as usize]).await;
+    res?;
+    if !methods.contains(&NO_AUTH) {
+        let (res, _) = stream.write_all(vec![VERSION, NO_ACCEPTABLE_METHODS]).await;
+        res?;
+        return Err(invalid_data("socks5 client requires authentication"));
+    }
+    let (res, _) = stream.write_all(vec![VERSION, NO_AUTH]).await;
+    res?;
+
+    // VER CMD RSV ATYP, then the address.
+    let (res, request) = stream.read_exact(vec![0; 4]).await;
+    res?;
+    if request[0] != VERSION {
+        return Err(invalid_data("unsupported socks version"));
+    }
+    let mut address = vec![request[3]];
+    let left = match request[3] {
+        ATYP_IPV4 => 4 + 2,
+        ATYP_IPV6 => 16 + 2,
+        ATYP_DOMAIN => {
+            let (res, len) = stream.read_exact(vec![0; 1]).await;
+            res?;
+            address.push(len[0]);
+            len[0] as usize + 2
+        }
+        _ => {
+            reply(stream, REP_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(invalid_data("unsupported socks5 address type"));
+        }
+    };
+    let (res, rest) = stream.read_exact(vec![0; left]).await;
+    res?;
+    address.extend_from_slice(&rest);
+    let (address, _) = Address::decode(&address)?;
+    if request[1] != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid_data("only socks5 CONNECT is supported"));
+    }
+    reply(stream, REP_SUCCEEDED).await?;
+    Ok(address)
+}
+
+/// Do SOCKS5 handshake with a proxy, so stream is connected to target through it.
+/// Username/password auth is offered if auth is set.
+pub async fn connect<S: AsyncReadRent + AsyncWriteRent>(
+    stream: &mut S,
+    target: &Address,
+    auth: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let greeting = match auth {
+        Some(_) => vec![VERSION, 2, NO_AUTH, USER_PASSWORD],
+        None => vec![VERSION, 1, NO_AUTH],
+    };
+    let (res, _) = stream.write_all(greeting).await;
+    res?;
+    let (res, method) = stream.read_exact(vec![0; 2]).await;
+    res?;
+    if method[0] != VERSION {
+        return Err(invalid_data("unsupported socks version"));
+    }
+    match (method[1], auth) {
+        (NO_AUTH, _) => (),
+        (USER_PASSWORD, Some((user, password))) => {
+            if user.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+                return Err(invalid_input("socks5 user or password too long"));
+            }
+            let mut request = vec![AUTH_VERSION, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            let (res, _) = stream.write_all(request).await;
+            res?;
+            let (res, status) = stream.read_exact(vec![0; 2]).await;
+            res?;
+            if status[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "socks5 proxy rejected the user or password",
+                ));
+            }
+        }
+        _ => return Err(invalid_data("socks5 proxy requires authentication")),
+    }
+
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    target.encode(&mut request);
+    let (res, _) = stream.write_all(request).await;
+    res?;
+    // VER REP RSV ATYP, then the bound address.
+    let (res, head) = stream.read_exact(vec![0; 4]).await;
+    res?;
+    if head[0] != VERSION {
+        return Err(invalid_data("unsupported socks version"));
+    }
+    if head[1] != REP_SUCCEEDED {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "socks5 proxy refused to connect {target}: {}",
+                reply_reason(head[1])
+            ),
+        ));
+    }
+    let left = match head[3] {
+        ATYP_IPV4 => 4 + 2,
+        ATYP_IPV6 => 16 + 2,
+        ATYP_DOMAIN => {
+            let (res, len) = stream.read_exact(vec![0; 1]).await;
+            res?;
+            len[0] as usize + 2
+        }
+        _ => return Err(invalid_data("unsupported socks5 address type")),
+    };
+    let (res, _) = stream.read_exact(vec![0; left]).await;
+    res?;
+    Ok(())
+}
+
+fn reply_reason(rep: u8) -> &'static str {
+    match rep {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "ttl expired",
+        REP_COMMAND_NOT_SUPPORTED => "command not supported",
+        REP_ADDRESS_TYPE_NOT_SUPPORTED => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn reply<S: AsyncWriteRent>(stream: &mut S, rep: u8) -> io::Result<()> {
+    // Bound address is not meaningful for a tunnel, so 0.0.0.0:0 is replied.
+    let (res, _) = stream
+        .write_all(vec![VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await;
+    res?;
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_codec() {
+        for address in [
+            Address::Socket("1.2.3.4:443".parse().unwrap()),
+            Address::Socket("[::1]:8080".parse().unwrap()),
+            Address::Domain("example.com".to_string(), 80),
+        ] {
+            let mut buf = Vec::new();
+            address.encode(&mut buf);
+            buf.extend_from_slice(b"left");
+            let (decoded, len) = Address::decode(&buf).unwrap();
+            assert_eq!(decoded, address);
+            assert_eq!(&buf[len..], b"left");
+            assert!(Address::decode(&buf[..len - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_connect_through_accept() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let proxy = monoio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accept(&mut stream).await.unwrap()
+            });
+            let mut stream = monoio::net::TcpStream::connect(addr).await.unwrap();
+            let target = Address::parse("example.com:443").unwrap();
+            // No auth is taken even if offered.
+            connect(&mut stream, &target, Some(("user", "password")))
+                .await
+                .unwrap();
+            assert_eq!(proxy.await, target);
+            assert_eq!(
+                Address::parse("[::1]:53").unwrap(),
+                Address::Socket("[::1]:53".parse().unwrap())
+            );
+            assert!(Address::parse("example.com").is_err());
+        });
+    }
+}
@@ -1,6 +1,10 @@
-//! Stream wrapper to calculate hmac.
+//! Stream wrappers to calculate hmac or replay data already read.
 
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    os::unix::io::{AsRawFd, RawFd},
+    rc::Rc,
+};
 
 use hmac::Mac;
 use monoio::{
@@ -53,9 +57,40 @@ impl<S> HashedStream for HashedReadStream<S> {
     }
 }
 
+/// PrefixedReadStream returns prefix to reads before reading the inner stream.
+pub struct PrefixedReadStream<S> {
+    raw: S,
+    prefix: Vec<u8>,
+    offset: usize,
+}
+
+// # Safety
+// Here we does not make read and write related, so if S is Split, Self is Split.
+unsafe impl<S: monoio::io::Split> monoio::io::Split for PrefixedReadStream<S> {}
+
+impl<S: AsRawFd> AsRawFd for PrefixedReadStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.raw.as_raw_fd()
+    }
+}
+
+impl<S> PrefixedReadStream<S> {
+    pub fn new(raw: S, prefix: Vec<u8>) -> Self {
+        Self {
+            raw,
+            prefix,
+            offset: 0,
+        }
+    }
+}
+
+// Enough for the ServerHello message at the head of the handshake server's flight.
+const HEAD_SIZE: usize = 512;
+
 pub struct HashedWriteStream<S> {
     raw: S,
-    hmac: Rc<RefCell<(bool, hmac::Hmac<sha1::Sha1>)>>,
+    hmacs: Rc<RefCell<(bool, Vec<hmac::Hmac<sha1::Sha1>>)>>,
+    head: Vec<u8>,
 }
 
 // # Safety
@@ -75,10 +110,19 @@ impl<S: AsWriteFd> AsWriteFd for HashedWriteStream<S> {
 }
 
 impl<S> HashedWriteStream<S> {
-    pub fn new(raw: S, password: &[u8]) -> Result<Self, hmac::digest::InvalidLength> {
+    /// Create a stream calculating hmac for every password at the same time.
+    pub fn new<P: AsRef<[u8]>>(
+        raw: S,
+        passwords: &[P],
+    ) -> Result<Self, hmac::digest::InvalidLength> {
+        let hmacs = passwords
+            .iter()
+            .map(|p| hmac::Hmac::new_from_slice(p.as_ref()))
+            .collect::<Result<_, _>>()?;
         Ok(Self {
             raw,
-            hmac: Rc::new(RefCell::new((true, hmac::Hmac::new_from_slice(password)?))),
+            hmacs: Rc::new(RefCell::new((true, hmacs))),
+            head: Vec::new(),
         })
     }
 
@@ -86,36 +130,34 @@ impl<S> HashedWriteStream<S> {
         self.raw
     }
 
-    pub fn hash(&self) -> [u8; 20] {
-        self.hmac
-            .borrow()
-            .clone()
-            .1
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .try_into()
-            .expect("unexpected digest length")
+    /// First bytes written while hmac is enabled.
+    pub fn head(&self) -> &[u8] {
+        &self.head
     }
 
     pub fn hmac_handler(&self) -> HmacHandler {
-        HmacHandler(self.hmac.clone())
+        HmacHandler(self.hmacs.clone())
     }
 }
 
-pub struct HmacHandler(Rc<RefCell<(bool, hmac::Hmac<sha1::Sha1>)>>);
+pub struct HmacHandler(Rc<RefCell<(bool, Vec<hmac::Hmac<sha1::Sha1>>)>>);
 
 impl HmacHandler {
-    pub fn hash(&self) -> [u8; 20] {
+    /// Hashes of each password, in the same order as passwords.
+    pub fn hashes(&self) -> Vec<[u8; 20]> {
         self.0
             .borrow()
-            .clone()
             .1
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .try_into()
-            .expect("unexpected digest length")
+            .iter()
+            .map(|h| {
+                h.clone()
+                    .finalize()
+                    .into_bytes()
+                    .as_slice()
+                    .try_into()
+                    .expect("unexpected digest length")
+            })
+            .collect()
     }
 
     pub fn disable(&mut self) {
@@ -123,12 +165,6 @@ impl HmacHandler {
     }
 }
 
-impl<S> HashedStream for HashedWriteStream<S> {
-    fn hash_stream(&self) -> [u8; 20] {
-        self.hash()
-    }
-}
-
 impl<S: AsyncReadRent> AsyncReadRent for HashedReadStream<S> {
     type ReadFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
         B: monoio::buf::IoBufMut + 'a, S: 'a;
@@ -224,10 +260,13 @@ impl<S: AsyncWriteRent> AsyncWriteRent for HashedWriteStream<S> {
             let ptr = buf.read_ptr();
             let (result, buf) = self.raw.write(buf).await;
             if let Ok(n) = result {
-                let mut eh = self.hmac.borrow_mut();
+                let mut eh = self.hmacs.borrow_mut();
                 if eh.0 {
                     // Safety: we can make sure the ptr and n are valid.
-                    eh.1.update(unsafe { std::slice::from_raw_parts(ptr, n) });
+                    let data = unsafe { std::slice::from_raw_parts(ptr, n) };
+                    eh.1.iter_mut().for_each(|h| h.update(data));
+                    let len = (HEAD_SIZE - self.head.len()).min(n);
+                    self.head.extend_from_slice(&data[..len]);
                 }
             }
             (result, buf)
@@ -254,3 +293,75 @@ impl<S: AsyncWriteRent> AsyncWriteRent for HashedWriteStream<S> {
         self.raw.shutdown()
     }
 }
+
+impl<S: AsyncReadRent> AsyncReadRent for PrefixedReadStream<S> {
+    type ReadFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
+        B: monoio::buf::IoBufMut + 'a, S: 'a;
+    type ReadvFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
+        B: monoio::buf::IoVecBufMut + 'a, S: 'a;
+
+    fn read<T: monoio::buf::IoBufMut>(&mut self, mut buf: T) -> Self::ReadFuture<'_, T> {
+        async move {
+            let left = &self.prefix[self.offset..];
+            if left.is_empty() {
+                return self.raw.read(buf).await;
+            }
+            let n = left.len().min(buf.bytes_total());
+            // Safety: n is within both the prefix and the buffer.
+            unsafe {
+                std::ptr::copy_nonoverlapping(left.as_ptr(), buf.write_ptr(), n);
+                buf.set_init(n);
+            }
+            self.offset += n;
+            if self.offset == self.prefix.len() {
+                self.prefix = Vec::new();
+                self.offset = 0;
+            }
+            (Ok(n), buf)
+        }
+    }
+
+    fn readv<T: monoio::buf::IoVecBufMut>(&mut self, mut buf: T) -> Self::ReadvFuture<'_, T> {
+        async move {
+            let slice = match IoVecWrapperMut::new(buf) {
+                Ok(slice) => slice,
+                Err(buf) => return (Ok(0), buf),
+            };
+
+            let (result, slice) = self.read(slice).await;
+            buf = slice.into_inner();
+            if let Ok(n) = result {
+                unsafe { buf.set_init(n) };
+            }
+            (result, buf)
+        }
+    }
+}
+
+impl<S: AsyncWriteRent> AsyncWriteRent for PrefixedReadStream<S> {
+    type WriteFuture<'a, T> = S::WriteFuture<'a, T> where
+    T: monoio::buf::IoBuf + 'a, Self: 'a;
+
+    type WritevFuture<'a, T>= S::WritevFuture<'a, T> where
+    T: monoio::buf::IoVecBuf + 'a, Self: 'a;
+
+    type FlushFuture<'a> = S::FlushFuture<'a> where Self: 'a;
+
+    type ShutdownFuture<'a> = S::ShutdownFuture<'a> where Self: 'a;
+
+    fn write<T: monoio::buf::IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+        self.raw.write(buf)
+    }
+
+    fn writev<T: monoio::buf::IoVecBuf>(&mut self, buf_vec: T) -> Self::WritevFuture<'_, T> {
+        self.raw.writev(buf_vec)
+    }
+
+    fn flush(&mut self) -> Self::FlushFuture<'_> {
+        self.raw.flush()
+    }
+
+    fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+        self.raw.shutdown()
+    }
+}
@@ -0,0 +1,194 @@
+//! Shadow-tls servers of the client, one is picked per connection by a load balancing policy.
+
+use std::{
+    cell::Cell,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Consecutive connect failures before an upstream is skipped.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long a failing upstream is skipped.
+const SKIP_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LbPolicy {
+    RoundRobin,
+    Random,
+}
+
+struct Upstream<A> {
+    address: A,
+    failures: Cell<u32>,
+    skip_until: Cell<Option<Instant>>,
+}
+
+/// Health check results of upstreams, shared by all workers.
+pub struct Health {
+    addresses: Vec<String>,
+    healthy: Vec<AtomicBool>,
+}
+
+impl Health {
+    /// All upstreams are healthy until checked.
+    pub fn new(addresses: Vec<String>) -> Self {
+        let healthy = addresses.iter().map(|_| AtomicBool::new(true)).collect();
+        Self { addresses, healthy }
+    }
+
+    pub fn is_healthy(&self, idx: usize) -> bool {
+        self.healthy[idx].load(Ordering::Relaxed)
+    }
+
+    /// Set health of an upstream, returns whether it changed.
+    pub fn set(&self, idx: usize, healthy: bool) -> bool {
+        self.healthy[idx].swap(healthy, Ordering::Relaxed) != healthy
+    }
+
+    /// Render health in prometheus text format.
+    pub fn render(&self) -> String {
+        let name = "shadow_tls_upstream_healthy";
+        let mut out = format!(
+            "# HELP {name} Whether the upstream passed its last health check.\n# TYPE {name} gauge\n"
+        );
+        for (address, healthy) in self.addresses.iter().zip(self.healthy.iter()) {
+            let healthy = healthy.load(Ordering::Relaxed) as u8;
+            let _ = writeln!(out, "{name}{{address=\"{address}\"}} {healthy}");
+        }
+        out
+    }
+}
+
+/// Upstreams of a worker, with connect failures tracked to skip dead ones for a while.
+/// Unhealthy ones are skipped too.
+pub struct Upstreams<A> {
+    upstreams: Vec<Upstream<A>>,
+    policy: LbPolicy,
+    next: Cell<usize>,
+    health: Arc<Health>,
+}
+
+impl<A> Upstreams<A> {
+    /// Create upstreams with health of the same addresses in the same order.
+    pub fn new(addresses: Vec<A>, policy: LbPolicy, health: Arc<Health>) -> anyhow::Result<Self> {
+        if addresses.is_empty() {
+            anyhow::bail!("at least one server address is required");
+        }
+        if addresses.len() != health.addresses.len() {
+            anyhow::bail!(
+                "health of {} upstreams is given for {} upstreams",
+                health.addresses.len(),
+                addresses.len()
+            );
+        }
+        let upstreams = addresses
+            .into_iter()
+            .map(|address| Upstream {
+                address,
+                failures: Cell::new(0),
+                skip_until: Cell::new(None),
+            })
+            .collect();
+        Ok(Self {
+            upstreams,
+            policy,
+            next: Cell::new(0),
+            health,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn address(&self, idx: usize) -> &A {
+        &self.upstreams[idx].address
+    }
+
+    /// Indexes of upstreams to try in order for a new connection: the one picked by policy,
+    /// then the ones after it. Skipped and unhealthy upstreams are moved to the end as a
+    /// last resort.
+    pub fn candidates(&self, now: Instant) -> Vec<usize> {
+        let len = self.upstreams.len();
+        let start = match self.policy {
+            LbPolicy::RoundRobin => {
+                let start = self.next.get();
+                self.next.set((start + 1) % len);
+                start
+            }
+            LbPolicy::Random => monoio::utils::thread_rng_n(len as u32) as usize,
+        };
+        let (mut healthy, skipped): (Vec<_>, Vec<_>) =
+            (0..len).map(|i| (start + i) % len).partition(|&i| {
+                self.health.is_healthy(i)
+                    && !matches!(self.upstreams[i].skip_until.get(), Some(t) if t > now)
+            });
+        healthy.extend(skipped);
+        healthy
+    }
+
+    pub fn record_success(&self, idx: usize) {
+        let upstream = &self.upstreams[idx];
+        upstream.failures.set(0);
+        upstream.skip_until.set(None);
+    }
+
+    /// Count a connect failure, returns true if the upstream starts being skipped.
+    pub fn record_failure(&self, idx: usize, now: Instant) -> bool {
+        let upstream = &self.upstreams[idx];
+        let failures = upstream.failures.get() + 1;
+        upstream.failures.set(failures);
+        if failures < FAILURE_THRESHOLD {
+            return false;
+        }
+        // Skipped again at once if it still fails after the skip expires.
+        upstream.failures.set(FAILURE_THRESHOLD - 1);
+        upstream.skip_until.set(Some(now + SKIP_DURATION));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstreams() {
+        let health = Arc::new(Health::new(vec!["a".into(), "b".into(), "c".into()]));
+        let upstreams =
+            Upstreams::new(vec!["a", "b", "c"], LbPolicy::RoundRobin, health.clone()).unwrap();
+        let now = Instant::now();
+        assert_eq!(upstreams.candidates(now), vec![0, 1, 2]);
+        assert_eq!(upstreams.candidates(now), vec![1, 2, 0]);
+
+        assert!(!upstreams.record_failure(1, now));
+        assert!(!upstreams.record_failure(1, now));
+        assert!(upstreams.record_failure(1, now));
+        assert_eq!(upstreams.candidates(now), vec![2, 0, 1]);
+        assert_eq!(upstreams.candidates(now), vec![0, 2, 1]);
+        assert_eq!(upstreams.candidates(now + SKIP_DURATION), vec![1, 2, 0]);
+
+        // One more failure after the skip expires skips it again.
+        assert!(upstreams.record_failure(1, now + SKIP_DURATION));
+        upstreams.record_success(1);
+        assert_eq!(upstreams.candidates(now), vec![2, 0, 1]);
+        assert!(!upstreams.record_failure(1, now));
+
+        assert!(health.set(0, false));
+        assert!(!health.set(0, false));
+        assert_eq!(upstreams.candidates(now), vec![1, 2, 0]);
+        assert!(health
+            .render()
+            .contains("shadow_tls_upstream_healthy{address=\"a\"} 0\n"));
+
+        assert!(Upstreams::<&str>::new(Vec::new(), LbPolicy::Random, health).is_err());
+    }
+}
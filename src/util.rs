@@ -1,15 +1,29 @@
 use std::{
+    cell::Cell,
     future::Future,
     marker::PhantomData,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    os::unix::io::{AsRawFd, RawFd},
     pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use monoio::{
     buf::{IoBuf, IoBufMut},
-    io::AsyncWriteRentExt,
-    net::TcpStream,
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Split, Splitable},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    error::RelayError,
+    limit::{Limiter, Permit, Semaphore, TotalLimits},
+    metrics::{ByteCounter, Metrics, Relayed},
+    Opts, PortRange,
 };
 
 pin_project_lite::pin_project! {
@@ -153,23 +167,39 @@ where
     Ok(())
 }
 
-// BUF_SIZE < u16::MAX, BUF_SIZE > HEADER_SIZE + copy_with_application_data::N
-// 4K or 8K is enough.
-const BUF_SIZE: usize = 4096;
+// Buffer size is configured by --buffer-size, it must be larger than HEADER_SIZE + N.
+// Frames written by copy_with_application_data are capped to MAX_FRAME_SIZE to keep
+// the length fit in u16 and look like normal tls records(2^14 bytes plus overhead).
+const MAX_FRAME_SIZE: usize = HEADER_SIZE + 16384 + 256;
 // HEADER_SIZE: 0 is application data, 1-2 is tls1.2, 3-4 is payload length.
 const HEADER_SIZE: usize = 5;
 pub const APPLICATION_DATA: u8 = 0x17;
 
+/// Wrap data into a single application data frame.
+pub fn application_data_frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+    frame.extend_from_slice(&[APPLICATION_DATA, 0x03, 0x03]);
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Copy reader to writer wrapped in application data frames. On EOF only the write
+/// direction of writer is shut down, so a half-closed connection keeps relaying the other
+/// direction.
 pub async fn copy_with_application_data<'a, const N: usize, R, W>(
     reader: &'a mut R,
     writer: &'a mut W,
     write_prefix: Option<[u8; N]>,
+    buf_size: usize,
+    counter: &ByteCounter<'_>,
+    limiter: &Limiter<'_>,
 ) -> std::io::Result<u64>
 where
     R: monoio::io::AsyncReadRent + ?Sized,
     W: monoio::io::AsyncWriteRent + ?Sized,
 {
-    let mut buf: Vec<u8> = vec![0; BUF_SIZE];
+    let mut buf: Vec<u8> = vec![0; buf_size.min(MAX_FRAME_SIZE)];
     buf[0] = APPLICATION_DATA;
     // 0x03, 0x03: tls 1.2
     buf[1] = 0x03;
@@ -191,7 +221,7 @@ where
     let mut transfered: u64 = 0;
     loop {
         let (read_res, buf_read) = reader.read(buf).await;
-        match read_res {
+        let read = match read_res {
             Ok(n) if n == 0 => {
                 // read closed
                 break;
@@ -208,8 +238,10 @@ where
             Ok(n) => {
                 // go write data
                 tracing::debug!("copy_with_application_data: read {n} bytes data");
+                limiter.acquire(n).await;
+                n
             }
-        }
+        };
         let mut raw_buf = buf_read.into_inner();
         // convert n to u16 is safe since the buffer will not be resized.
         let n_u16 = (raw_buf.len() - HEADER_SIZE) as u16;
@@ -228,6 +260,7 @@ where
         let (write_res, buf_) = writer.write_all(raw_buf).await;
         let n = write_res?;
         transfered += n as u64;
+        counter.add(read as u64);
         tracing::debug!("reset buf slice with {} bytes prefix", HEADER_SIZE);
         buf = buf_.slice_mut(HEADER_SIZE..);
     }
@@ -235,15 +268,58 @@ where
     Ok(transfered)
 }
 
+/// Copy reader to writer as is, for protocol v1 which does not frame data after the
+/// handshake. Shuts down the write direction of writer on EOF like
+/// copy_with_application_data.
+pub async fn copy_plain<'a, R, W>(
+    reader: &'a mut R,
+    writer: &'a mut W,
+    buf_size: usize,
+    counter: &ByteCounter<'_>,
+    limiter: &Limiter<'_>,
+) -> std::io::Result<u64>
+where
+    R: monoio::io::AsyncReadRent + ?Sized,
+    W: monoio::io::AsyncWriteRent + ?Sized,
+{
+    let mut buf: Vec<u8> = vec![0; buf_size];
+    let mut transfered: u64 = 0;
+    loop {
+        let (read_res, buf_read) = reader.read(buf).await;
+        let n = match read_res {
+            Ok(0) => break,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                buf = buf_read;
+                continue;
+            }
+            Err(e) => return Err(e),
+            Ok(n) => n,
+        };
+        limiter.acquire(n).await;
+        let (write_res, buf_) = writer.write_all(buf_read.slice(..n)).await;
+        write_res?;
+        transfered += n as u64;
+        counter.add(n as u64);
+        buf = buf_.into_inner();
+    }
+    let _ = writer.shutdown().await;
+    Ok(transfered)
+}
+
+/// Copy application data frames from reader to writer without the frame headers, shutting
+/// down the write direction of writer on EOF like copy_with_application_data.
 pub async fn copy_without_application_data<'a, R, W>(
     reader: &'a mut R,
     writer: &'a mut W,
+    buf_size: usize,
+    counter: &ByteCounter<'_>,
+    limiter: &Limiter<'_>,
 ) -> std::io::Result<u64>
 where
     R: monoio::io::AsyncReadRent + ?Sized,
     W: monoio::io::AsyncWriteRent + ?Sized,
 {
-    let mut buf: Vec<u8> = vec![0; BUF_SIZE];
+    let mut buf: Vec<u8> = vec![0; buf_size];
     let mut to_copy = 0;
     let mut transfered: u64 = 0;
 
@@ -312,6 +388,7 @@ where
                 continue 'r;
             }
             let copy_size = to_copy.min(initialized);
+            limiter.acquire(copy_size).await;
             let write_slice = raw_buf.slice(read_index..read_index + copy_size);
 
             let (write_res, buf_) = writer.write_all(write_slice).await;
@@ -319,6 +396,7 @@ where
             read_index += n;
             to_copy -= n;
             transfered += n as u64;
+            counter.add(n as u64);
             raw_buf = buf_.into_inner();
         }
     }
@@ -326,13 +404,1059 @@ where
     Ok(transfered)
 }
 
-pub fn mod_tcp_conn(conn: &mut TcpStream, keepalive: bool, nodelay: bool) {
-    if keepalive {
+/// Same as copy_without_application_data, but payloads are spliced to the writer through
+/// a pipe instead of being copied to userspace. Frame headers are still read normally.
+#[cfg(target_os = "linux")]
+pub async fn splice_without_application_data<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    counter: &ByteCounter<'_>,
+    limiter: &Limiter<'_>,
+) -> std::io::Result<u64>
+where
+    R: monoio::io::AsyncReadRent + monoio::io::as_fd::AsReadFd,
+    W: AsyncWriteRent + monoio::io::as_fd::AsWriteFd,
+{
+    use monoio::io::{
+        splice::{SpliceDestination, SpliceSource},
+        AsyncReadRentExt,
+    };
+
+    let (mut pipe_r, mut pipe_w) = monoio::net::unix::new_pipe()?;
+    let mut header = vec![0; HEADER_SIZE];
+    let mut transfered: u64 = 0;
+    'r: loop {
+        let (read_res, header_) = reader.read_exact(header).await;
+        header = header_;
+        match read_res {
+            Ok(_) => (),
+            // read closed
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        if header[0] != APPLICATION_DATA {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "unexpected tls content type",
+            ));
+        }
+        let mut to_copy = u16::from_be_bytes([header[3], header[4]]) as u32;
+        while to_copy > 0 {
+            let n = reader.splice_to_pipe(&mut pipe_w, to_copy).await?;
+            if n == 0 {
+                break 'r;
+            }
+            limiter.acquire(n as usize).await;
+            let mut in_pipe = n;
+            while in_pipe > 0 {
+                let written = writer.splice_from_pipe(&mut pipe_r, in_pipe).await?;
+                if written == 0 {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                in_pipe -= written;
+            }
+            to_copy -= n;
+            transfered += n as u64;
+            counter.add(n as u64);
+        }
+    }
+    let _ = writer.shutdown().await;
+    Ok(transfered)
+}
+
+/// Open another handle of a tcp socket, so one direction can be spliced while the split
+/// halves of the original stream keep serving the other one.
+#[cfg(target_os = "linux")]
+pub fn dup_tcp_stream(fd: RawFd) -> std::io::Result<TcpStream> {
+    use std::os::unix::io::FromRawFd;
+
+    // The original stream still owns fd, so it must not be closed here.
+    let stream = std::mem::ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+    TcpStream::from_std(stream.try_clone()?)
+}
+
+/// Side of the relay a connection is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Accepted from the listen address.
+    Inbound,
+    /// Connected by us.
+    Outbound,
+}
+
+/// Set keepalive and nodelay of a connection, keepalive is disabled if keepalive_idle is 0.
+pub fn mod_tcp_conn(conn: &mut TcpStream, opts: &Opts, direction: Direction) {
+    if opts.keepalive_idle != 0 {
         let _ = conn.set_tcp_keepalive(
-            Some(Duration::from_secs(90)),
-            Some(Duration::from_secs(90)),
-            Some(2),
+            Some(Duration::from_secs(opts.keepalive_idle)),
+            Some(Duration::from_secs(opts.keepalive_interval)),
+            Some(opts.keepalive_count),
         );
     }
-    let _ = conn.set_nodelay(nodelay);
+    let _ = conn.set_nodelay(opts.nodelay(direction));
+    if let Some(dscp) = opts.dscp {
+        set_dscp(&socket2::SockRef::from(&*conn), dscp);
+    }
+}
+
+/// Mark packets of socket with dscp in the upper 6 bits of the traffic class.
+/// Ipv6 sockets get IP_TOS as well, which applies to ipv4-mapped peers.
+fn set_dscp(socket: &socket2::Socket, dscp: u8) {
+    let tos = (dscp as u32) << 2;
+    let is_ipv6 = matches!(socket.domain(), Ok(socket2::Domain::IPV6));
+    if is_ipv6 {
+        let value = tos as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                &value as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            warn_dscp_failed(&std::io::Error::last_os_error());
+        }
+    }
+    if let Err(e) = socket.set_tos(tos) {
+        if !is_ipv6 {
+            warn_dscp_failed(&e);
+        }
+    }
+}
+
+fn warn_dscp_failed(e: &std::io::Error) {
+    static WARN: Once = Once::new();
+    WARN.call_once(|| tracing::warn!("Set DSCP failed: {e}"));
+}
+
+/// Make closing conn send RST instead of FIN, by SO_LINGER with zero timeout.
+pub fn reset_on_close(conn: &TcpStream) {
+    let _ = socket2::SockRef::from(conn).set_linger(Some(Duration::ZERO));
+}
+
+/// Sockets shut down when the guard is dropped, so a relay dropped before it finishes,
+/// like on shutdown timeout, closes its connections at once instead of leaving them to
+/// pending io_uring ops, which monoio does not cancel.
+/// The guard keeps its own handle of each socket, so shutting down never hits a reused fd
+/// whatever order the streams are dropped in. This doubles the fds of a relay, see
+/// FDS_PER_CONNECTION.
+#[derive(Default)]
+pub struct ShutdownGuard(Vec<(RawFd, socket2::Socket)>);
+
+impl ShutdownGuard {
+    /// Shut down the socket of fd on drop.
+    pub fn watch(&mut self, fd: RawFd) -> std::io::Result<()> {
+        use std::os::unix::io::FromRawFd;
+
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.0
+            .push((fd, unsafe { socket2::Socket::from_raw_fd(dup) }));
+        Ok(())
+    }
+
+    /// Shut down the socket of fd now, for a stream dropped before the relay finishes.
+    pub fn release(&mut self, fd: RawFd) {
+        self.0.retain(|(watched, socket)| {
+            if *watched == fd {
+                let _ = socket.shutdown(std::net::Shutdown::Both);
+            }
+            *watched != fd
+        });
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        for (_, socket) in self.0.iter() {
+            let _ = socket.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// Fds a relayed connection takes: its two sockets and their handles in ShutdownGuard.
+pub const FDS_PER_CONNECTION: u64 = 4;
+
+/// Soft limit of open fds, None if unlimited or unknown.
+pub fn open_files_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+/// Like monoio::time::timeout, but on expiry the sockets are shut down and the future
+/// is polled to its end instead of being dropped.
+/// Dropped io_uring ops are not cancelled by monoio, so a pending read would otherwise
+/// keep its socket open until the peer sends something.
+pub async fn timeout_or_shutdown<F: Future>(
+    duration: Duration,
+    fds: &[RawFd],
+    fut: F,
+) -> std::io::Result<F::Output> {
+    monoio::pin!(fut);
+    match monoio::time::timeout(duration, &mut fut).await {
+        Ok(output) => Ok(output),
+        Err(_) => {
+            for fd in fds {
+                unsafe { libc::shutdown(*fd, libc::SHUT_RDWR) };
+            }
+            let _ = fut.await;
+            Err(std::io::ErrorKind::TimedOut.into())
+        }
+    }
+}
+
+/// Wait up to the handshake timeout for a handshake permit, if --max-pending-handshakes
+/// is set.
+pub async fn handshake_permit<'a>(
+    permits: Option<&'a Semaphore>,
+    opts: &Opts,
+    metrics: &Metrics,
+) -> std::io::Result<Option<Permit<'a>>> {
+    let permits = match permits {
+        Some(permits) => permits,
+        None => return Ok(None),
+    };
+    let acquire = permits.acquire();
+    monoio::pin!(acquire);
+    let permit =
+        monoio::time::timeout(Duration::from_secs(opts.handshake_timeout), &mut acquire).await;
+    if acquire.queued() {
+        Metrics::inc(&metrics.queued_handshakes);
+    }
+    permit
+        .map(Some)
+        .map_err(|_| std::io::ErrorKind::TimedOut.into())
+}
+
+/// Why a relay was cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expired {
+    /// No data for idle_timeout.
+    Idle,
+    /// Open for max_connection_duration.
+    MaxDuration,
+}
+
+impl std::fmt::Display for Expired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Idle => write!(f, "idle timeout"),
+            Self::MaxDuration => write!(f, "max connection duration"),
+        }
+    }
+}
+
+/// Like timeout_or_shutdown, but fut expires after being idle for idle_timeout, with
+/// the idle time reset whenever activity is updated, or at deadline regardless of
+/// activity. Zero idle_timeout never expires for idle.
+pub async fn relay_until_expired<F: Future>(
+    idle_timeout: Duration,
+    deadline: Option<Instant>,
+    activity: &Cell<Instant>,
+    fds: &[RawFd],
+    fut: F,
+) -> Result<F::Output, Expired> {
+    monoio::pin!(fut);
+    let expired = loop {
+        let idle_deadline = (!idle_timeout.is_zero()).then(|| activity.get() + idle_timeout);
+        let next = match (idle_deadline, deadline) {
+            (None, None) => return Ok(fut.await),
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+        };
+        if let Ok(output) = monoio::time::timeout_at(next.into(), &mut fut).await {
+            return Ok(output);
+        }
+        let now = Instant::now();
+        if matches!(deadline, Some(deadline) if deadline <= now) {
+            break Expired::MaxDuration;
+        }
+        if !idle_timeout.is_zero() && activity.get() + idle_timeout <= now {
+            break Expired::Idle;
+        }
+    };
+    for fd in fds {
+        unsafe { libc::shutdown(*fd, libc::SHUT_RDWR) };
+    }
+    let _ = fut.await;
+    Err(expired)
+}
+
+/// Relay in_stream and out_stream as is, for --plain-relay which tells issues of the
+/// shadow-tls layer from those of the network. Counted, limited and expired like the
+/// relays after a handshake.
+pub async fn relay_plain<S>(
+    mut in_stream: S,
+    mut out_stream: TcpStream,
+    opts: &Opts,
+    metrics: &Metrics,
+    total_limits: Option<&TotalLimits>,
+    start: Instant,
+) -> Result<Relayed, RelayError>
+where
+    S: AsyncReadRent + AsyncWriteRent + Split + AsRawFd,
+{
+    let fds = [in_stream.as_raw_fd(), out_stream.as_raw_fd()];
+    let mut guard = ShutdownGuard::default();
+    for fd in fds {
+        guard.watch(fd)?;
+    }
+    let (mut out_r, mut out_w) = out_stream.split();
+    let (mut in_r, mut in_w) = in_stream.split();
+    let activity = Cell::new(Instant::now());
+    let outbound_limiter = Limiter::new(
+        opts.rate_limit,
+        total_limits.map(|l| &l.outbound),
+        &activity,
+    );
+    let inbound_limiter =
+        Limiter::new(opts.rate_limit, total_limits.map(|l| &l.inbound), &activity);
+    let inbound_counter = ByteCounter::new(&metrics.bytes_inbound);
+    let outbound_counter = ByteCounter::new(&metrics.bytes_outbound);
+    let relay = async {
+        monoio::join!(
+            copy_plain(
+                &mut out_r,
+                &mut in_w,
+                opts.buffer_bytes(),
+                &outbound_counter,
+                &outbound_limiter,
+            ),
+            copy_plain(
+                &mut in_r,
+                &mut out_w,
+                opts.buffer_bytes(),
+                &inbound_counter,
+                &inbound_limiter,
+            )
+        )
+    };
+    let relayed = relay_until_expired(
+        Duration::from_secs(opts.idle_timeout),
+        opts.max_connection_deadline(start),
+        &activity,
+        &fds,
+        relay,
+    )
+    .await;
+    let summary = Relayed {
+        inbound: inbound_counter.get(),
+        outbound: outbound_counter.get(),
+        duration: start.elapsed(),
+    };
+    match relayed {
+        Ok((Ok(_), Ok(_))) => Ok(summary),
+        Ok((Err(e), _)) | Ok((_, Err(e))) => Err(RelayError::Relay(summary, e.into())),
+        Err(expired) => {
+            tracing::info!("Relay closed for {expired}");
+            Ok(summary)
+        }
+    }
+}
+
+// Connection Attempt Delay recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect with Happy Eyeballs(RFC 8305).
+/// Resolved addresses are tried with ipv6 first and families interleaved, a new attempt
+/// starts when the previous one fails or ATTEMPT_DELAY elapsed, and the first connected
+/// one wins. The whole operation is bounded by connect_timeout.
+/// If fast_open is set, TCP_FASTOPEN_CONNECT is enabled before connecting, so the SYN
+/// is sent together with the first write.
+/// If bind_interface or bind_addr is set, the socket is bound before connecting and
+/// a failed bind fails the connection instead of falling back to the default route.
+pub async fn connect<A: ToSocketAddrs>(addr: A, opts: &Opts) -> std::io::Result<TcpStream> {
+    let deadline = monoio::time::Instant::now() + Duration::from_secs(opts.connect_timeout);
+    connect_until(addr, opts, deadline).await
+}
+
+/// Connect like connect, but bounded by deadline instead of connect_timeout.
+pub async fn connect_until<A: ToSocketAddrs>(
+    addr: A,
+    opts: &Opts,
+    deadline: monoio::time::Instant,
+) -> std::io::Result<TcpStream> {
+    let addrs = sort_addrs(addr.to_socket_addrs()?, opts.bind_addr);
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "empty address",
+        ));
+    }
+    let mut next = addrs.iter();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut last_err = None;
+    let result = loop {
+        if let Some(addr) = next.next() {
+            match start_connect(*addr, opts) {
+                Ok(attempt) => attempts.push(attempt),
+                Err(e) => {
+                    last_err = Some(e);
+                    continue;
+                }
+            }
+        }
+        if attempts.is_empty() {
+            break Err(last_err.unwrap());
+        }
+        let wake = match next.len() {
+            0 => deadline,
+            _ => deadline.min(monoio::time::Instant::now() + ATTEMPT_DELAY),
+        };
+        let sleep = monoio::time::sleep_until(wake);
+        monoio::pin!(sleep);
+        let finished = std::future::poll_fn(|cx| {
+            for (idx, (_, attempt)) in attempts.iter_mut().enumerate() {
+                if let Poll::Ready(r) = attempt.as_mut().poll(cx) {
+                    return Poll::Ready(Some((idx, r)));
+                }
+            }
+            sleep.as_mut().poll(cx).map(|_| None)
+        })
+        .await;
+        match finished {
+            Some((idx, r)) => {
+                attempts.swap_remove(idx);
+                match r {
+                    Ok(stream) => break Ok(stream),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            None if wake == deadline => {
+                break Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connect timed out",
+                ))
+            }
+            None => {}
+        }
+    };
+    // Like timeout_or_shutdown, pending attempts must be shut down and polled to the end
+    // to release their sockets.
+    for (fd, _) in attempts.iter() {
+        unsafe { libc::shutdown(*fd, libc::SHUT_RDWR) };
+    }
+    for (_, attempt) in attempts {
+        let _ = attempt.await;
+    }
+    result
+}
+
+/// Interleave address families with ipv6 first.
+/// Addresses of the other family are dropped if bind_addr is set since binding them fails.
+fn sort_addrs(
+    addrs: impl Iterator<Item = SocketAddr>,
+    bind_addr: Option<IpAddr>,
+) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs
+        .filter(|a| bind_addr.map_or(true, |ip| ip.is_ipv4() == a.is_ipv4()))
+        .partition(SocketAddr::is_ipv6);
+    let mut sorted = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
+}
+
+/// A connecting socket and the future to wait for it.
+type Attempt = (
+    RawFd,
+    Pin<Box<dyn Future<Output = std::io::Result<TcpStream>>>>,
+);
+
+/// Create a socket and start connecting.
+fn start_connect(addr: SocketAddr, opts: &Opts) -> std::io::Result<Attempt> {
+    let (socket, in_progress) = match opts.source_port_range {
+        Some(range) => connect_from_range(addr, opts, range)?,
+        None => connect_socket(addr, opts, opts.bind_addr.map(|ip| SocketAddr::new(ip, 0)))?,
+    };
+    let mut stream = TcpStream::from_std(socket.into())?;
+    let fd = stream.as_raw_fd();
+    Ok((
+        fd,
+        Box::pin(async move {
+            if in_progress {
+                // Wait for writable like monoio does, then check if connect failed.
+                // The write reports and clears the pending error if it is done already.
+                let (res, _) = stream.write(&[]).await;
+                res?;
+                if let Some(e) = socket2::SockRef::from(&stream).take_error()? {
+                    return Err(e);
+                }
+            }
+            Ok(stream)
+        }),
+    ))
+}
+
+/// Create a socket bound to local if any, and start connecting it to addr. Returns the
+/// socket and whether the connect is in progress.
+fn connect_socket(
+    addr: SocketAddr,
+    opts: &Opts,
+    local: Option<SocketAddr>,
+) -> std::io::Result<(socket2::Socket, bool)> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if let Some(interface) = opts.bind_interface.as_ref() {
+        bind_interface(&socket, interface).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("bind to interface {interface} failed: {e}"),
+            )
+        })?;
+    }
+    if let Some(local) = local {
+        // Fixed ports are reused once their connections are in TIME_WAIT.
+        if local.port() != 0 {
+            socket.set_reuse_address(true)?;
+        }
+        socket
+            .bind(&local.into())
+            .map_err(|e| std::io::Error::new(e.kind(), format!("bind to {local} failed: {e}")))?;
+    }
+    if opts.fast_open {
+        set_fast_open(&socket, FastOpen::Connect);
+    }
+    // Set before connecting so the window scale is chosen for the buffer.
+    set_buffer_sizes(&socket, opts);
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(_) => Ok((socket, false)),
+        Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => Ok((socket, true)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Connect from the ports of range in turn, starting after the last one tried by any
+/// connection so connections cycle through the range. Ports in use fail binding, or
+/// connecting if another connection from the port goes to addr, and are skipped.
+fn connect_from_range(
+    addr: SocketAddr,
+    opts: &Opts,
+    range: PortRange,
+) -> std::io::Result<(socket2::Socket, bool)> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let ip = opts.bind_addr.unwrap_or(match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    let mut last_err = None;
+    for _ in 0..range.count() {
+        let port = range.start + (NEXT.fetch_add(1, Ordering::Relaxed) % range.count()) as u16;
+        match connect_socket(addr, opts, Some(SocketAddr::new(ip, port))) {
+            Ok(connecting) => return Ok(connecting),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable => {
+                    last_err = Some(e)
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+    let e = last_err.expect("port range is not empty");
+    Err(std::io::Error::new(
+        e.kind(),
+        format!("no port of source port range {range} is free, last error: {e}"),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_interface(socket: &socket2::Socket, interface: &str) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_interface(_: &socket2::Socket, _: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unsupported platform",
+    ))
+}
+
+/// Pin the current thread to cpu.
+#[cfg(target_os = "linux")]
+pub fn pin_thread_to_cpu(cpu: usize) -> std::io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_thread_to_cpu(_: usize) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// User and group switched to by --user and --group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+#[cfg(unix)]
+impl Credentials {
+    /// Look up user and group names, numeric ids are taken as is. The group defaults to
+    /// the primary group of user, and the user to the current one if only group is set.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> anyhow::Result<Option<Self>> {
+        let (uid, primary_gid) = match user {
+            None if group.is_none() => return Ok(None),
+            None => (unsafe { libc::getuid() }, None),
+            Some(user) => {
+                let name = std::ffi::CString::new(user)?;
+                let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+                match (passwd.is_null(), user.parse()) {
+                    (false, _) => unsafe { ((*passwd).pw_uid, Some((*passwd).pw_gid)) },
+                    (true, Ok(uid)) => {
+                        let passwd = unsafe { libc::getpwuid(uid) };
+                        (
+                            uid,
+                            (!passwd.is_null()).then(|| unsafe { (*passwd).pw_gid }),
+                        )
+                    }
+                    (true, Err(_)) => anyhow::bail!("unknown user {user}"),
+                }
+            }
+        };
+        let gid = match group {
+            Some(group) => {
+                let name = std::ffi::CString::new(group)?;
+                let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+                match (entry.is_null(), group.parse()) {
+                    (false, _) => unsafe { (*entry).gr_gid },
+                    (true, Ok(gid)) => gid,
+                    (true, Err(_)) => anyhow::bail!("unknown group {group}"),
+                }
+            }
+            None => primary_gid
+                .ok_or_else(|| anyhow::anyhow!("user {uid} has no primary group, set --group"))?,
+        };
+        Ok(Some(Self { uid, gid }))
+    }
+
+    /// Clear supplementary groups and set gid then uid, of all threads of the process.
+    pub fn apply(&self) -> std::io::Result<()> {
+        unsafe {
+            if libc::setgroups(0, std::ptr::null()) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl Credentials {
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> anyhow::Result<Option<Self>> {
+        match (user, group) {
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("--user and --group are only supported on unix"),
+        }
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Set SO_SNDBUF and SO_RCVBUF from opts if any. The sizes in effect are logged once,
+/// since the kernel doubles them and caps them by net.core.wmem_max and rmem_max.
+pub fn set_buffer_sizes(socket: &socket2::Socket, opts: &Opts) {
+    if opts.sndbuf.is_none() && opts.rcvbuf.is_none() {
+        return;
+    }
+    if let Some(size) = opts.sndbuf {
+        if let Err(e) = socket.set_send_buffer_size(size as usize) {
+            tracing::debug!("set SO_SNDBUF failed: {e}");
+        }
+    }
+    if let Some(size) = opts.rcvbuf {
+        if let Err(e) = socket.set_recv_buffer_size(size as usize) {
+            tracing::debug!("set SO_RCVBUF failed: {e}");
+        }
+    }
+    static LOG: Once = Once::new();
+    LOG.call_once(|| {
+        let effective = |size: std::io::Result<usize>| match size {
+            Ok(size) => format!("{size}B"),
+            Err(e) => format!("unknown({e})"),
+        };
+        tracing::info!(
+            "Socket buffers in effect: sndbuf {}, rcvbuf {}",
+            effective(socket.send_buffer_size()),
+            effective(socket.recv_buffer_size())
+        );
+    });
+}
+
+/// Enable TCP_FASTOPEN on listener.
+pub fn set_fast_open_listener(listener: &TcpListener) {
+    set_fast_open(&socket2::SockRef::from(listener), FastOpen::Listen);
+}
+
+enum FastOpen {
+    Listen,
+    Connect,
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &socket2::Socket, mode: FastOpen) {
+    // For listener the value is the queue length of pending fast open requests.
+    let (opt, value) = match mode {
+        FastOpen::Listen => (libc::TCP_FASTOPEN, 256),
+        FastOpen::Connect => (libc::TCP_FASTOPEN_CONNECT, 1),
+    };
+    let value: libc::c_int = value;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            opt,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        warn_fast_open_unavailable(&std::io::Error::last_os_error().to_string());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_: &socket2::Socket, _: FastOpen) {
+    warn_fast_open_unavailable("unsupported platform");
+}
+
+fn warn_fast_open_unavailable(reason: &str) {
+    static WARN: Once = Once::new();
+    WARN.call_once(|| tracing::warn!("TCP fast open is not available: {reason}"));
+}
+
+const PASSWORD_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Generate a random alphanumeric password from the system CSPRNG.
+/// Bytes beyond the largest multiple of charset length are discarded to avoid modulo bias.
+pub fn gen_password(length: usize) -> std::io::Result<String> {
+    let limit = 256 - 256 % PASSWORD_CHARSET.len();
+    let mut urandom = std::fs::File::open("/dev/urandom")?;
+    let mut password = String::with_capacity(length);
+    let mut buf = [0; 64];
+    while password.len() < length {
+        std::io::Read::read_exact(&mut urandom, &mut buf)?;
+        password.extend(
+            buf.iter()
+                .filter(|&&b| (b as usize) < limit)
+                .map(|&b| PASSWORD_CHARSET[b as usize % PASSWORD_CHARSET.len()] as char)
+                .take(length - password.len()),
+        );
+    }
+    Ok(password)
+}
+
+/// Check addr is a host:port pair, IPv6 hosts must be bracketed like `[::1]:443`.
+/// Only the form is checked, host names are resolved on connect.
+pub fn validate_address(kind: &str, addr: &str) -> anyhow::Result<()> {
+    let reason = match address_error(addr) {
+        None => return Ok(()),
+        Some(reason) => reason,
+    };
+    anyhow::bail!("invalid {kind} address '{addr}' ({reason})")
+}
+
+fn address_error(addr: &str) -> Option<&'static str> {
+    if addr.contains("://") {
+        return Some("unexpected scheme, expect host:port");
+    }
+    let (host, port) = match addr.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = match rest.split_once(']') {
+                Some(split) => split,
+                None => return Some("missing closing bracket"),
+            };
+            if host.parse::<std::net::Ipv6Addr>().is_err() {
+                return Some("invalid IPv6 address");
+            }
+            match rest.strip_prefix(':') {
+                Some(port) => (host, port),
+                None => return Some("missing port"),
+            }
+        }
+        None => match addr.rsplit_once(':') {
+            None => return Some("missing port"),
+            Some((host, _)) if host.contains(':') => {
+                return Some("IPv6 address must be bracketed like [::1]:443")
+            }
+            Some(split) => split,
+        },
+    };
+    if host.is_empty() {
+        return Some("missing host");
+    }
+    if host.contains(|c: char| c.is_whitespace() || c == '/') {
+        return Some("invalid host");
+    }
+    if port.parse::<u16>().is_err() {
+        return Some("invalid port");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU64;
+
+    use monoio::io::{AsyncReadRent, Splitable};
+
+    use super::*;
+
+    /// Writer of a slow reader, accepting at most 7 bytes per write and stalling now and then.
+    struct SlowWriter(Vec<u8>, usize);
+
+    impl AsyncWriteRent for SlowWriter {
+        type WriteFuture<'a, T>
+            = impl Future<Output = monoio::BufResult<usize, T>> + 'a
+        where
+            T: IoBuf + 'a;
+        type WritevFuture<'a, T>
+            = impl Future<Output = monoio::BufResult<usize, T>> + 'a
+        where
+            T: monoio::buf::IoVecBuf + 'a;
+        type FlushFuture<'a> = impl Future<Output = std::io::Result<()>> + 'a;
+        type ShutdownFuture<'a> = impl Future<Output = std::io::Result<()>> + 'a;
+
+        fn write<T: IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+            async move {
+                self.1 += 1;
+                if self.1 % 500 == 0 {
+                    monoio::time::sleep(Duration::from_millis(1)).await;
+                }
+                let n = buf.bytes_init().min(7);
+                // Safety: n is within the initialized part of buf.
+                self.0
+                    .extend_from_slice(unsafe { std::slice::from_raw_parts(buf.read_ptr(), n) });
+                (Ok(n), buf)
+            }
+        }
+
+        fn writev<T: monoio::buf::IoVecBuf>(&mut self, buf: T) -> Self::WritevFuture<'_, T> {
+            async move { (Ok(0), buf) }
+        }
+
+        fn flush(&mut self) -> Self::FlushFuture<'_> {
+            async move { Ok(()) }
+        }
+
+        fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+            async move { Ok(()) }
+        }
+    }
+
+    #[test]
+    fn test_copy_partial_writes() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let total = AtomicU64::new(0);
+            let counter = ByteCounter::new(&total);
+            let activity = Cell::new(Instant::now());
+            let limiter = Limiter::new(None, None, &activity);
+
+            let mut framed = SlowWriter(Vec::new(), 0);
+            copy_with_application_data::<0, _, _>(
+                &mut data.as_slice(),
+                &mut framed,
+                None,
+                1000,
+                &counter,
+                &limiter,
+            )
+            .await
+            .unwrap();
+            assert_eq!(counter.get(), data.len() as u64);
+
+            let mut plain = SlowWriter(Vec::new(), 0);
+            copy_without_application_data(
+                &mut framed.0.as_slice(),
+                &mut plain,
+                1000,
+                &counter,
+                &limiter,
+            )
+            .await
+            .unwrap();
+            assert!(plain.0 == data, "relayed data differs");
+        });
+    }
+
+    #[test]
+    fn test_relay_half_close() {
+        async fn read_to_end(stream: &mut TcpStream) -> Vec<u8> {
+            let mut data = Vec::new();
+            let mut buf = vec![0; 4096];
+            loop {
+                let (res, buf_) = stream.read(buf).await;
+                buf = buf_;
+                match res.unwrap() {
+                    0 => return data,
+                    n => data.extend_from_slice(&buf[..n]),
+                }
+            }
+        }
+
+        let mut rt = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+            let proxy_addr = proxy.local_addr().unwrap();
+            let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+            // Relay like the client: frame what the client sends, unframe the replies.
+            let relay = monoio::spawn(async move {
+                let (mut in_stream, _) = proxy.accept().await.unwrap();
+                let mut out_stream = TcpStream::connect(upstream_addr).await.unwrap();
+                let (mut in_r, mut in_w) = in_stream.split();
+                let (mut out_r, mut out_w) = out_stream.split();
+                let total = AtomicU64::new(0);
+                let counter = ByteCounter::new(&total);
+                let activity = Cell::new(Instant::now());
+                let limiter = Limiter::new(None, None, &activity);
+                ErrGroup::new(
+                    copy_with_application_data::<0, _, _>(
+                        &mut in_r, &mut out_w, None, 4096, &counter, &limiter,
+                    ),
+                    copy_without_application_data(&mut out_r, &mut in_w, 4096, &counter, &limiter),
+                )
+                .await
+                .unwrap();
+            });
+            // Answer only after the request is half-closed.
+            let server = monoio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let request = read_to_end(&mut stream).await;
+                let (res, _) = stream.write_all(application_data_frame(b"pong")).await;
+                res.unwrap();
+                stream.shutdown().await.unwrap();
+                request
+            });
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let (res, _) = client.write_all(b"ping").await;
+            res.unwrap();
+            client.shutdown().await.unwrap();
+            assert_eq!(read_to_end(&mut client).await, b"pong");
+            assert_eq!(server.await, application_data_frame(b"ping"));
+            relay.await;
+        });
+    }
+
+    #[test]
+    fn test_validate_address() {
+        for addr in [
+            "1.2.3.4:443",
+            "[::1]:8080",
+            "cloud.tencent.com:443",
+            "0.0.0.0:0",
+        ] {
+            assert!(validate_address("server", addr).is_ok(), "{addr}");
+        }
+        for (addr, reason) in [
+            ("cloud.tencent.com", "missing port"),
+            ("::1:443", "IPv6 address must be bracketed like [::1]:443"),
+            ("[::1]", "missing port"),
+            ("[::1:443", "missing closing bracket"),
+            ("[a.com]:443", "invalid IPv6 address"),
+            (":443", "missing host"),
+            ("a.com:https", "invalid port"),
+            ("a.com:65536", "invalid port"),
+            ("https://a.com:443", "unexpected scheme, expect host:port"),
+        ] {
+            assert_eq!(address_error(addr), Some(reason), "{addr}");
+        }
+        assert_eq!(
+            validate_address("server", "cloud.tencent.com")
+                .unwrap_err()
+                .to_string(),
+            "invalid server address 'cloud.tencent.com' (missing port)"
+        );
+    }
+
+    #[test]
+    fn test_set_dscp() {
+        let v4 = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        v4.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        set_dscp(&v4, 46);
+        assert_eq!(v4.tos().unwrap(), 46 << 2);
+
+        let v6 = match socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None) {
+            Ok(v6) => v6,
+            Err(_) => return,
+        };
+        if v6
+            .bind(&"[::1]:0".parse::<SocketAddr>().unwrap().into())
+            .is_err()
+        {
+            return;
+        }
+        set_dscp(&v6, 10);
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                v6.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(value, 10 << 2);
+    }
 }
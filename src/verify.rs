@@ -0,0 +1,331 @@
+//! Server certificate verification of the handshake on client side.
+
+use std::{
+    cell::RefCell, collections::HashMap, fmt::Display, str::FromStr, sync::Arc, time::SystemTime,
+};
+
+use clap::ValueEnum;
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, Error, ServerName,
+};
+use sha2::{Digest, Sha256};
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+
+/// Pin is the base64 encoded SHA-256 hash of a certificate's SubjectPublicKeyInfo, the same
+/// format as HPKP pins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin([u8; 32]);
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hash = base64::decode(s)?;
+        let hash = hash
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("expect base64 of a 32 bytes sha256 hash, got {s}"))?;
+        Ok(Self(hash))
+    }
+}
+
+impl Pin {
+    /// Pin of the public key of a certificate.
+    pub fn of(cert: &Certificate) -> Option<Self> {
+        subject_public_key_info(&cert.0).map(|spki| Self(Sha256::digest(spki).into()))
+    }
+}
+
+impl Display for Pin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", base64::encode(self.0))
+    }
+}
+
+/// PinnedVerifier verifies the certificate with inner verifier, and then requires the leaf
+/// certificate's public key to match one of the pins.
+pub struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<Pin>,
+}
+
+impl PinnedVerifier {
+    pub fn new(inner: Arc<dyn ServerCertVerifier>, pins: Vec<Pin>) -> Self {
+        Self { inner, pins }
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pin = Pin::of(end_entity).ok_or(Error::InvalidCertificateEncoding)?;
+        if !self.pins.contains(&pin) {
+            tracing::error!(
+                "Certificate pin mismatch for {server_name:?}: got {pin}, expect one of {:?}, the tls server may be intercepted",
+                self.pins.iter().map(Pin::to_string).collect::<Vec<_>>()
+            );
+            return Err(Error::InvalidCertificateData(
+                "certificate pin mismatch".to_string(),
+            ));
+        }
+        Ok(verified)
+    }
+}
+
+/// RenamedVerifier verifies the certificate with inner verifier for a fixed name instead
+/// of the sni sent, for handshake servers whose certificate does not cover the sni.
+pub struct RenamedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    name: ServerName,
+}
+
+impl RenamedVerifier {
+    pub fn new(inner: Arc<dyn ServerCertVerifier>, name: ServerName) -> Self {
+        Self { inner, name }
+    }
+}
+
+impl ServerCertVerifier for RenamedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// What to do when a handshake server presents another public key than before.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertChangeAction {
+    Log,
+    Reject,
+}
+
+/// CertWatch remembers the public key each server name presented first, and reports
+/// later handshakes presenting another one: a rotation, or the handshake intercepted.
+/// On log the new key is remembered so a rotation warns once, on reject the first one is
+/// kept so every later handshake with the new key fails until restart.
+pub struct CertWatch {
+    action: CertChangeAction,
+    seen: RefCell<HashMap<String, Pin>>,
+}
+
+impl CertWatch {
+    pub fn new(action: CertChangeAction) -> Self {
+        Self {
+            action,
+            seen: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, server_name: &str, cert: &Certificate) -> anyhow::Result<()> {
+        let pin = Pin::of(cert).ok_or_else(|| anyhow::anyhow!("invalid certificate encoding"))?;
+        let mut seen = self.seen.borrow_mut();
+        let last = match seen.get_mut(server_name) {
+            Some(last) if *last != pin => last,
+            Some(_) => return Ok(()),
+            None => {
+                seen.insert(server_name.to_string(), pin);
+                return Ok(());
+            }
+        };
+        match self.action {
+            CertChangeAction::Log => {
+                tracing::warn!("Certificate of {server_name} changed from {last} to {pin}, rotated or the tls server may be intercepted");
+                *last = pin;
+                Ok(())
+            }
+            CertChangeAction::Reject => {
+                tracing::error!("Certificate of {server_name} changed from {last} to {pin}, rejected, the tls server may be intercepted");
+                anyhow::bail!("certificate of {server_name} changed")
+            }
+        }
+    }
+}
+
+struct Element<'a> {
+    tag: u8,
+    /// The whole encoded element.
+    raw: &'a [u8],
+    content: &'a [u8],
+}
+
+/// NoVerifier accepts any certificate. The handshake is only camouflage and the data is
+/// protected by the inner protocol, so this is only a matter of the handshake server choice.
+pub struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Split the leading DER element from data, returns it with the remaining data.
+fn der_element(data: &[u8]) -> Option<(Element, &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        0x81..=0x84 => {
+            let n = (first & 0x7f) as usize;
+            if rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+            (len, &rest[n..])
+        }
+        _ => return None,
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = data.len() - rest.len();
+    let element = Element {
+        tag,
+        raw: &data[..header + len],
+        content: &rest[..len],
+    };
+    Some((element, &rest[len..]))
+}
+
+/// Find the DER encoded SubjectPublicKeyInfo in a X.509 certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let sequence = |data| {
+        der_element(data)
+            .map(|(e, _)| e)
+            .filter(|e| e.tag == TAG_SEQUENCE)
+    };
+    let mut tbs = sequence(sequence(cert)?.content)?.content;
+    if tbs.first() == Some(&TAG_VERSION) {
+        tbs = der_element(tbs)?.1;
+    }
+    // Skip serialNumber, signature, issuer, validity and subject.
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.1;
+    }
+    sequence(tbs).map(|spki| spki.raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal certificate with spki as the public key.
+    fn cert(spki: &[u8]) -> Vec<u8> {
+        let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01];
+        for _ in 0..4 {
+            tbs.extend_from_slice(&[0x30, 0x00]);
+        }
+        tbs.extend_from_slice(spki);
+        tbs.extend_from_slice(&[0xa3, 0x00]);
+        let mut cert = vec![0x30, 0x81, tbs.len() as u8 + 2 + 2, 0x30, tbs.len() as u8];
+        cert.extend_from_slice(&tbs);
+        cert.extend_from_slice(&[0x30, 0x00]);
+        cert
+    }
+
+    #[test]
+    fn test_subject_public_key_info() {
+        let spki = [0x30, 0x03, 0x02, 0x01, 0x07];
+        let cert = cert(&spki);
+        assert_eq!(subject_public_key_info(&cert), Some(&spki[..]));
+        assert_eq!(subject_public_key_info(&cert[..cert.len() - 4]), None);
+
+        let pin: Pin = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            .parse()
+            .unwrap();
+        assert_eq!(pin, Pin(Sha256::digest(b"").into()));
+        assert!("AAAA".parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn test_renamed_verifier() {
+        struct Expect(ServerName);
+        impl ServerCertVerifier for Expect {
+            fn verify_server_cert(
+                &self,
+                _: &Certificate,
+                _: &[Certificate],
+                server_name: &ServerName,
+                _: &mut dyn Iterator<Item = &[u8]>,
+                _: &[u8],
+                _: SystemTime,
+            ) -> Result<ServerCertVerified, Error> {
+                match *server_name == self.0 {
+                    true => Ok(ServerCertVerified::assertion()),
+                    false => Err(Error::InvalidCertificateData("wrong name".to_string())),
+                }
+            }
+        }
+        let name = |s| ServerName::try_from(s).unwrap();
+        let verifier = RenamedVerifier::new(Arc::new(Expect(name("b.com"))), name("b.com"));
+        let cert = Certificate(Vec::new());
+        let verify = |sni| {
+            verifier.verify_server_cert(
+                &cert,
+                &[],
+                &name(sni),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+        };
+        assert!(verify("a.com").is_ok());
+        assert!(verify("b.com").is_ok());
+    }
+
+    #[test]
+    fn test_cert_watch() {
+        let first = Certificate(cert(&[0x30, 0x03, 0x02, 0x01, 0x07]));
+        let rotated = Certificate(cert(&[0x30, 0x03, 0x02, 0x01, 0x08]));
+        let log = CertWatch::new(CertChangeAction::Log);
+        assert!(log.check("a.com", &first).is_ok());
+        assert!(log.check("b.com", &rotated).is_ok());
+        assert!(log.check("a.com", &rotated).is_ok());
+        assert_eq!(log.seen.borrow()["a.com"], Pin::of(&rotated).unwrap());
+
+        let reject = CertWatch::new(CertChangeAction::Reject);
+        assert!(reject.check("a.com", &first).is_ok());
+        assert!(reject.check("a.com", &rotated).is_err());
+        assert!(reject.check("a.com", &rotated).is_err());
+        assert!(reject.check("a.com", &first).is_ok());
+    }
+}